@@ -0,0 +1,47 @@
+//! 標準入力の行の異なり数を見積もる`uniq | wc -l`のようなツール。
+//!
+//! 使い方: `cargo run --example distinct -- [-b 精度] < input.txt`
+
+extern crate hyperloglog;
+
+use std::env;
+use std::error::Error;
+use std::io::{self, BufRead};
+use std::process;
+
+use hyperloglog::HyperLogLog;
+
+/// 精度の既定値。エラー率は約0.8%
+const DEFAULT_B: u8 = 14;
+
+/// コマンドライン引数から精度(b)を読み取る
+fn parse_b(args: &[String]) -> Result<u8, Box<dyn Error>> {
+    match args {
+        [] => Ok(DEFAULT_B),
+        [flag, value] if flag == "-b" => Ok(value.parse()?),
+        _ => Err(From::from("usage: distinct [-b precision]")),
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let mut hll = HyperLogLog::new(parse_b(&args)?)?;
+    let stdin = io::stdin();
+    let mut lines = 0u64;
+    for line in stdin.lock().lines() {
+        hll.insert(&line?);
+        lines += 1;
+    }
+    let (est, method) = hll.cardinality_with_method();
+    println!("lines:     {}", lines);
+    println!("distinct:  {:.0} (±{:.1}%)", est, hll.typical_error_rate() * 100.0);
+    println!("estimator: {:?}", method);
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
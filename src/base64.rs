@@ -0,0 +1,71 @@
+//! 標準のBase64(RFC 4648、パディングあり)の最小限の実装。
+//! 依存クレートを増やさないため、ここで実装する
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// bytesをBase64の文字列に変換する
+pub fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |acc, (i, &x)| acc | (x as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Base64の文字列をバイト列に戻す。長さが4の倍数でない、Base64以外の文字を含む、
+/// またはパディングの位置が正しくなければ`None`を返す
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None
+    }
+    let mut decoded = Vec::with_capacity(s.len() / 4 * 3);
+    for (k, chunk) in s.chunks(4).enumerate() {
+        let is_last = k == s.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !is_last) {
+            return None
+        }
+        let mut n = 0u32;
+        for (i, &c) in chunk[..4 - padding].iter().enumerate() {
+            let v = ALPHABET.iter().position(|&a| a == c)? as u32;
+            n |= v << (18 - 6 * i);
+        }
+        for i in 0..(3 - padding) {
+            decoded.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // RFC 4648 10節のテストベクタ
+    #[test]
+    fn rfc4648_vectors() {
+        let vectors = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="),
+                       ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")];
+        for &(plain, encoded) in &vectors {
+            assert_eq!(encode(plain.as_bytes()), encoded);
+            assert_eq!(decode(encoded).unwrap(), plain.as_bytes());
+        }
+
+        let bytes = (0..=255).collect::<Vec<u8>>();
+        assert_eq!(decode(&encode(&bytes)).unwrap(), bytes);
+
+        assert!(decode("Zm9").is_none());
+        assert!(decode("Zm9*").is_none());
+        assert!(decode("Zg==Zm9v").is_none());
+        assert!(decode("Z===").is_none());
+    }
+}
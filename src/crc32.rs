@@ -0,0 +1,73 @@
+//! CRC-32(IEEE 802.3、zlibやPNGと同じもの)の最小限の実装。
+//! 依存クレートを増やさないため、ここで実装する
+
+/// 反転した生成多項式
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// 1バイトごとの剰余の表
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { POLYNOMIAL ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+/// 逐次的にCRC-32を求める。`update`でバイト列を追加し、`finish`で値を得る
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+
+    pub fn new() -> Self {
+        Crc32 { state: !0 }
+    }
+
+    /// bytesを追加する
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &x in bytes {
+            self.state = TABLE[((self.state ^ x as u32) & 0xff) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    /// これまでに追加したバイト列のCRC-32を返す
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+/// bytesのCRC-32を返す
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // よく使われるチェック値
+    #[test]
+    fn check_value() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"123456789"), 0xcbf4_3926);
+        assert_eq!(checksum(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+
+        let mut crc = Crc32::new();
+        crc.update(b"12345");
+        crc.update(b"6789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,282 @@
+//! レジスタの状態からカーディナリティを推定する関数
+
+use std::error::Error;
+
+use hash::HashWidth;
+use sketch::HyperLogLog;
+
+/// 推定アルゴリズム。デバッグ出力用
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Estimator {
+    HyperLogLog,
+    LinerCounting, // スモールレンジの見積もりに使用する
+    LargeRange,    // 32ビットのハッシュ値で、ラージレンジ補正を行った
+    Saturated      // レジスタが飽和しており、見積もり値は下限に過ぎない
+}
+
+/// 見積もりの計算に使う浮動小数点数の型。通常はf64で、`f32-estimate`フィーチャーを
+/// 有効にするとf32になる。f32では有効桁数が約7桁に減るため、見積もり値にもおよそ
+/// 10^-7の相対誤差が加わるが、典型的なエラー率(b = 16でも0.4%)に比べれば無視できる。
+/// 公開APIの見積もり値はどちらの場合もf64で返す
+#[cfg(not(feature = "f32-estimate"))]
+pub(crate) type Float = f64;
+#[cfg(feature = "f32-estimate")]
+pub(crate) type Float = f32;
+
+/// ビット数bに対応するα値の表。bが4未満の要素は使わないので0とする
+const ALPHA_TABLE: [Float; 17] = {
+    let mut table = [0.0; 17];
+    let mut b = 4;
+    while b <= 16 {
+        table[b] = alpha_formula(b as u8);
+        b += 1;
+    }
+    table
+};
+
+/// 論文にあるα値の定義
+const fn alpha_formula(b: u8) -> Float {
+    match b {
+        4 => 0.673, // α16
+        5 => 0.697, // α32
+        6 => 0.709, // α64
+        _ => 0.7213 / (1.0 + 1.079 / (1u32 << b) as Float),
+    }
+}
+
+/// ビット数bに対応するα値を返す。
+pub(crate) fn get_alpha(b: u8) -> Result<Float, Box<dyn Error>> {
+    if !(4..=16).contains(&b) {
+        Err(From::from(format!("b must be between 4 and 16. b = {}", b)))
+    } else {
+        Ok(ALPHA_TABLE[b as usize])
+    }
+}
+
+/// カーディナリティを推定し、その値と見積もりに使用したアルゴリズムを返す
+/// スモールレンジでは`Linear Counting`アルゴリズムを使用し、それを超えるレンジでは
+/// `HyperLogLog`アルゴリズムを使用する。ここまでは論文の通り。
+/// しかし、論文にあるラーレンジ補正は行わない。なぜなら、本実装では、32ビットの
+/// ハッシュ値の代わりに64ビットのハッシュ値を使用しており、ハッシュ値が衝突する
+/// 頻度が極めて低いと予想されるため。32ビットのハッシュ値を使う場合は補正を行う。
+/// ただし、すべてのレジスタが上限に達していると、それ以上要素を追加しても変化しないので、
+/// `Saturated`として見積もり値(下限)を返す。また、見積もり値はハッシュ値が取りうる値の数
+/// (2^64または2^32)を超えないようにする
+pub(crate) fn estimate_cardinality(hll: &HyperLogLog) -> (f64, Estimator) {
+    estimate_cardinality_with_alpha(hll, hll.alpha)
+}
+
+/// `estimate_cardinality`と同様にカーディナリティを推定する。ただし、α値に
+/// hllのものではなくalphaを使う
+pub(crate) fn estimate_cardinality_with_alpha(hll: &HyperLogLog, alpha: Float) -> (f64, Estimator) {
+    let (est, method) = estimate_cardinality_of_registers(alpha, &hll.registers);
+    let space = hll.hash_width.space();
+    if est >= space || hll.registers.iter().all(|&x| x == hll.register_cap) {
+        (est.min(space), Estimator::Saturated)
+    } else if hll.hash_width == HashWidth::Bits32 && est > space / 30.0 {
+        (large_range_correction(est, space), Estimator::LargeRange)
+    } else {
+        (est, method)
+    }
+}
+
+/// hllのレジスタに適用できるすべての推定アルゴリズムの見積もり値を返す。
+/// `HyperLogLog`は常に、`Linear Counting`は値が0のレジスタがあるとき、
+/// ラージレンジ補正は32ビットのハッシュ値で見積もり値がハッシュ値の空間より小さいときに含める。
+/// 値はアルゴリズムの選択や上限の制限を行わない、それぞれの式の値そのもの
+pub(crate) fn all_estimates(hll: &HyperLogLog) -> Vec<(Estimator, f64)> {
+    let m = hll.m as Float;
+    let (sum, zeros) = sum_and_count_zero_registers(&hll.registers);
+    let raw: Float = raw_hyperloglog_estimate(hll.alpha, m, sum);
+    let raw = raw as f64;
+    let mut estimates = vec![(Estimator::HyperLogLog, raw)];
+    if zeros > 0 {
+        let linear: Float = linear_counting_estimate(m, zeros as Float);
+        estimates.push((Estimator::LinerCounting, linear as f64));
+    }
+    let space = hll.hash_width.space();
+    if hll.hash_width == HashWidth::Bits32 && raw < space {
+        estimates.push((Estimator::LargeRange, large_range_correction(raw, space)));
+    }
+    estimates
+}
+
+/// ラージレンジ補正。ハッシュ値が取りうる値の数spaceに対して見積もり値estが大きいとき、
+/// ハッシュ値の衝突で数え落とした分を補正する。estはspace未満でなければならない
+pub(crate) fn large_range_correction(est: f64, space: f64) -> f64 {
+    -space * (1.0 - est / space).ln()
+}
+
+/// α値alphaとレジスタの配列registersからカーディナリティを推定する。
+/// 詳細は`estimate_cardinality`を参照
+pub(crate) fn estimate_cardinality_of_registers(alpha: Float, registers: &[u8]) -> (f64, Estimator) {
+    estimate_cardinality_of_values(alpha, registers.len(), registers.iter().cloned())
+}
+
+/// `estimate_cardinality_of_registers`と同じ推定を、m個のレジスタの値を返すイテレータ
+/// valuesから行う。レジスタを詰めて格納しているため、スライスを作れない場合に使う
+pub(crate) fn estimate_cardinality_of_values<I>(alpha: Float, m: usize, values: I) -> (f64, Estimator)
+    where I: Iterator<Item = u8>
+{
+    let m_64 = m as Float;
+    // レジスタは一度だけ走査し、見積もりに必要な値をまとめて求める
+    let (sum, zeros) = sum_and_count_zero_values(values);
+    // まず`HyperLogLog`アルゴリズムによる見積もり値を算出する
+    let est = raw_hyperloglog_estimate(alpha, m_64, sum);
+
+    if est < (5.0 / 2.0 * m_64) {
+        // スモールレンジの見積もりを行う。もし値が0のレジスタが一つでもあるならば
+        // `Linear Counting`アルゴリズムで見積もりし直す。
+        match zeros {
+            0 => (est as f64, Estimator::HyperLogLog),
+            v => (linear_counting_estimate(m_64, v as Float) as f64, Estimator::LinerCounting),
+        }
+    } else {
+        (est as f64, Estimator::HyperLogLog)
+    }
+}
+
+/// 各レジスタの値xについての2^(-x)の総和と、値が0のレジスタの個数を返す
+pub(crate) fn sum_and_count_zero_registers(registers: &[u8]) -> (Float, usize) {
+    sum_and_count_zero_values(registers.iter().cloned())
+}
+
+/// `sum_and_count_zero_registers`と同じ値を、レジスタの値を返すイテレータから求める
+fn sum_and_count_zero_values<I: Iterator<Item = u8>>(values: I) -> (Float, usize) {
+    values.fold((0.0, 0), |(sum, zeros), x| {
+        (sum + (2.0 as Float).powi(-(x as i32)), if x == 0 { zeros + 1 } else { zeros })
+    })
+}
+
+/// `HyperLogLog`アルゴリズムによる未補正の見積もり値を、
+/// `sum_and_count_zero_registers`で求めた総和sumから計算する
+pub(crate) fn raw_hyperloglog_estimate(alpha: Float, m: Float, sum: Float) -> Float {
+    alpha * m * m / sum
+}
+
+/// `Linear Counting`アルゴリズムによる見積もり値を算出する。
+/// 値が0のレジスタがない場合は定義されないので、一つだけある場合の値m * ln(m)を
+/// 上限として返す
+pub(crate) fn linear_counting_estimate(m: Float, number_of_zero_registers: Float) -> Float {
+    m * (m / number_of_zero_registers.max(1.0)).ln()
+}
+
+/// m個のレジスタを持つスケッチの`HyperLogLog`アルゴリズムの典型的なエラー率(標準誤差)を返す
+pub(crate) fn typical_error_rate(m: usize) -> f64 {
+    1.04 / (m as f64).sqrt()
+}
+
+/// m個のレジスタで見積もったカーディナリティnに対する、`Linear Counting`アルゴリズムの
+/// 標準誤差を返す。nが0なら0を返す
+pub(crate) fn linear_counting_error(m: f64, n: f64) -> f64 {
+    if n == 0.0 {
+        return 0.0
+    }
+    let t = n / m;
+    (m * (t.exp() - t - 1.0)).sqrt() / n
+}
+
+// テストケース
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn alpha_table_matches_formula() {
+        for b in 4..=16u8 {
+            let m = (1u32 << b) as Float;
+            let expected = match b {
+                4 => 0.673,
+                5 => 0.697,
+                6 => 0.709,
+                _ => 0.7213 / (1.0 + 1.079 / m),
+            };
+            assert_eq!(get_alpha(b).unwrap().to_bits(), expected.to_bits());
+        }
+        assert!(get_alpha(3).is_err());
+        assert!(get_alpha(17).is_err());
+    }
+
+    #[test]
+    fn linear_counting_without_zero_registers() {
+        let est = linear_counting_estimate(1024.0, 0.0);
+        assert!(est.is_finite());
+        assert_eq!(est, 1024.0 * (1024.0 as Float).ln());
+        assert_eq!(linear_counting_estimate(1024.0, 1024.0), 0.0);
+    }
+
+    // 浮動小数点数の加算は結合的でないため、レジスタの総和は加算の順序で僅かに変わる。
+    // 順序を変えても見積もり値の相対差が、m個の加算の丸め誤差の上限m * εに収まることを確かめる。
+    // 将来、並列に総和を求める場合もこの範囲に収まらなければならない
+    #[test]
+    fn estimate_independent_of_summation_order() {
+        let mut hll = HyperLogLog::new(16).unwrap();
+        for i in 0..200000 {
+            hll.insert(&i);
+        }
+        let m_64 = hll.m as Float;
+        let sequential = sum_and_count_zero_registers(&hll.registers).0;
+        let chunked = hll.registers.chunks(1024).rev()
+            .map(|chunk| sum_and_count_zero_registers(chunk).0)
+            .sum::<Float>();
+
+        let a = raw_hyperloglog_estimate(hll.alpha, m_64, sequential);
+        let b = raw_hyperloglog_estimate(hll.alpha, m_64, chunked);
+        let tolerance = m_64 * Float::EPSILON;
+        assert!(((a - b) / a).abs() <= tolerance, "{} vs {}", a, b);
+    }
+
+    #[test]
+    fn single_pass_estimate() {
+        let mut hll = HyperLogLog::new(16).unwrap();
+        for n in &[0, 10, 1000, 100000, 1000000] {
+            for i in 0..*n {
+                hll.insert(&i);
+            }
+            // レジスタを複数回走査する素朴な実装と完全に一致する
+            let m_64 = hll.m as Float;
+            let sum = hll.registers.iter().map(|&x| (2.0 as Float).powi(-(x as i32))).sum::<Float>();
+            let est = hll.alpha * m_64 * m_64 / sum;
+            let zeros = hll.registers.iter().filter(|&x| *x == 0).count();
+            let expected = if est < 5.0 / 2.0 * m_64 && zeros > 0 {
+                linear_counting_estimate(m_64, zeros as Float)
+            } else {
+                est
+            };
+            assert_eq!(estimate_cardinality(&hll).0, expected as f64);
+        }
+    }
+
+    #[test]
+    fn large_range_correction() {
+        // b = 4、すべてのレジスタがvのとき、未補正の見積もり値はα16 * 16 * 2^v
+        let mut hll = HyperLogLog::with_32bit_hash(4).unwrap();
+        let space = 2.0f64.powi(32);
+        for &(v, corrected) in &[(23, false), (24, true)] {
+            for j in 0..hll.m {
+                hll.set_register(j, v).unwrap();
+            }
+            let raw: Float = 0.673 * 16.0 * (2.0 as Float).powi(v as i32);
+            let raw = raw as f64;
+            assert_eq!(raw > space / 30.0, corrected);
+
+            let (est, method) = hll.cardinality_with_method();
+            if corrected {
+                assert_eq!(method, Estimator::LargeRange);
+                assert_eq!(est, -space * (1.0 - raw / space).ln());
+                assert!(est > raw);
+            } else {
+                assert_eq!(method, Estimator::HyperLogLog);
+                assert_eq!(est, raw);
+            }
+        }
+
+        // 64ビットのハッシュ値では補正しない
+        let mut hll = HyperLogLog::new(4).unwrap();
+        for j in 0..hll.m {
+            hll.set_register(j, 24).unwrap();
+        }
+        assert_eq!(hll.cardinality_with_method().1, Estimator::HyperLogLog);
+    }
+}
@@ -0,0 +1,152 @@
+//! レジスタの更新に使うハッシュ関数と、ハッシュ値からレジスタの値を求める関数
+
+use std::hash::{Hash, Hasher};
+
+/// SiphasherはRust1.13.0で非推奨になった。しかしそれを置き換えるSipHasher24は
+/// 現状では非安定(unstable)なため、安定版のRustリリースは利用できない。
+#[allow(deprecated)]
+use std::hash::SipHasher;
+use sip::SipHasher13;
+
+/// レジスタの更新に使うハッシュアルゴリズム。アルゴリズムが異なるスケッチは
+/// 同じ要素でも異なるレジスタを更新するため、互いにマージできない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    SipHash24, // デフォルト
+    SipHash13, // SipHash-2-4より高速
+}
+
+impl HashAlgorithm {
+
+    /// シリアライズなどで使う1バイトの識別子を返す
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            HashAlgorithm::SipHash24 => 0,
+            HashAlgorithm::SipHash13 => 1,
+        }
+    }
+
+    /// 識別子からアルゴリズムを求める。未知の識別子なら`None`を返す
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(HashAlgorithm::SipHash24),
+            1 => Some(HashAlgorithm::SipHash13),
+            _ => None,
+        }
+    }
+}
+
+/// ハッシュ値のビット数。32ビットは、32ビットのハッシュ値を使う他の実装と
+/// 見積もり値を合わせるためのもので、論文のラージレンジ補正を行う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashWidth {
+    Bits64, // デフォルト
+    Bits32,
+}
+
+impl HashWidth {
+
+    /// ビット数を返す
+    pub fn bits(self) -> u8 {
+        match self {
+            HashWidth::Bits64 => 64,
+            HashWidth::Bits32 => 32,
+        }
+    }
+
+    /// ハッシュ値が取りうる値の数(2^bits)を返す
+    pub(crate) fn space(self) -> f64 {
+        2.0f64.powi(self.bits() as i32)
+    }
+}
+
+/// ハッシュのキーの由来。見積もりには影響しないが、キーがどのように作られたかを
+/// 監査ログなどに残すために記録する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySource {
+    Os,        // OSの乱数生成器で作った
+    Seed(u64), // シードから決定的に導いた
+    Explicit,  // 呼び出し側が指定した(由来を記録していない旧形式から復元した場合も含む)
+}
+
+impl KeySource {
+
+    /// シリアライズなどで使う1バイトの識別子を返す
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            KeySource::Os => 0,
+            KeySource::Seed(_) => 1,
+            KeySource::Explicit => 2,
+        }
+    }
+
+    /// 識別子とシードからキーの由来を求める。シードは`Seed`の場合だけ使う。
+    /// 未知の識別子なら`None`を返す
+    pub(crate) fn from_tag(tag: u8, seed: u64) -> Option<Self> {
+        match tag {
+            0 => Some(KeySource::Os),
+            1 => Some(KeySource::Seed(seed)),
+            2 => Some(KeySource::Explicit),
+            _ => None,
+        }
+    }
+}
+
+/// シードからハッシュのキーの組を導く。プロセスやプラットフォームをまたいでも同じ値になる
+pub(crate) fn keys_from_seed(seed: u64) -> (u64, u64) {
+    let key = |i: u8| {
+        let mut hasher = fixed_key_hasher();
+        hasher.write(&seed.to_le_bytes());
+        hasher.write_u8(i);
+        hasher.finish()
+    };
+    (key(0), key(1))
+}
+
+/// アルゴリズムalgorithmとキー(key0, key1)で、valueに対する64ビットのハッシュ値を求める
+#[allow(deprecated)] // SipHasherがRust1.13.0で非推奨(deprecated)のため
+pub(crate) fn hash_value<H: Hash>(algorithm: HashAlgorithm, key0: u64, key1: u64, value: &H) -> u64 {
+    match algorithm {
+        HashAlgorithm::SipHash24 => {
+            let mut hasher = SipHasher::new_with_keys(key0, key1);
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+        HashAlgorithm::SipHash13 => {
+            let mut hasher = SipHasher13::new_with_keys(key0, key1);
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+    }
+}
+
+/// キーを(0, 0)に固定したSipHash-2-4のhasherを返す。プロセスをまたいでも
+/// 同じ値になる必要がある指紋の計算に使う
+#[allow(deprecated)] // SipHasherがRust1.13.0で非推奨(deprecated)のため
+pub(crate) fn fixed_key_hasher() -> impl Hasher {
+    SipHasher::new_with_keys(0, 0)
+}
+
+/// ハッシュ値のビット数がhash_width、アドレッシングのビット数がbのときに
+/// レジスタが取りうる最大値を返す。例えば64ビットのハッシュ値で残りの64 - bビットが
+/// すべて0のとき、`position_of_leftmost_one_bit`は65 - bを返す
+pub(crate) fn max_register_value(hash_width: HashWidth, b: u8) -> u8 {
+    hash_width.bits() - b + 1
+}
+
+/// ハッシュ値(64ビット符号なしの2進数)の左端からみて最初に出現した1の位置を返す
+/// 例: 10000... -> 1、00010... -> 4
+pub(crate) fn position_of_leftmost_one_bit(s: u64, max_width: u8) -> u8 {
+    count_leading_zeros(s, max_width) + 1
+}
+
+/// ハッシュ値(64ビット符号なし2進数)左端に連続して並んでいる0の個数を返す.
+/// 10000... -> 0、00010... -> 3
+fn count_leading_zeros(mut s: u64, max_width: u8) -> u8 {
+    let mut lz = max_width;
+    while s != 0 {
+        lz -= 1;
+        s >>= 1;
+    }
+    lz
+}
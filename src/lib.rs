@@ -1,227 +1,380 @@
 extern crate rand;
 
-use rand::Rng;
-use std::fmt;
+mod sip;
+mod base64;
+mod crc32;
+mod hash;
+mod estimate;
+mod sketch;
+mod view;
+mod packed;
+
 use std::error::Error;
-use std::hash::{Hash, Hasher};
-use std::collections::BTreeMap;
-
-/// SiphasherはRust1.13.0で非推奨になった。しかしそれを置き換えるSipHasher24は
-/// 現状では非安定(unstable)なため、安定版のRustリリースは利用できない。
-#[allow(deperaceted)]
-use std::hash::SipHasher;
-
-/// 推定アルゴリズム。デバッグ出力用
-pub enum Estimator {
-    HyperLogLog,
-    LinerCounting  // スモールレンジの見積もりに使用する
+use std::hash::Hash;
+use std::collections::VecDeque;
+use std::io;
+#[cfg(feature = "testing")]
+use std::collections::HashSet;
+
+pub use estimate::Estimator;
+pub use hash::{HashAlgorithm, HashWidth, KeySource};
+pub use sketch::{HyperLogLog, CompatibilityReport, SketchCompatibility};
+pub use view::HyperLogLogView;
+pub use packed::{PackedHyperLogLog, PACKED_REGISTER_CAP};
+use estimate::{get_alpha, typical_error_rate};
+#[cfg(feature = "testing")]
+use estimate::{Float, raw_hyperloglog_estimate, sum_and_count_zero_registers};
+
+/// `HyperLogLog`のレジスタ配列がbytesバイトに収まる最大のbを返す。レジスタは1個につき
+/// 1バイトを使う。`PackedHyperLogLog`はレジスタ配列にm / 2バイトを使うので、
+/// `precision_for_memory(bytes * 2)`でbytesバイトに収まる最大のbが求まる。
+/// b = 4でも収まらなければ`Err`を返す
+pub fn precision_for_memory(bytes: usize) -> Result<u8, Box<dyn Error>> {
+    (4..17).rev().find(|&b| 1usize << b <= bytes)
+        .ok_or_else(|| From::from(format!("at least {} bytes are required. bytes = {}", 1 << 4, bytes)))
+}
+
+/// 指定できる各b(4以上16以下)について、(b, 典型的なエラー率, レジスタ配列のバイト数)を
+/// bの小さい順に返す。精度とメモリのトレードオフを一覧するために使う
+pub fn precision_options() -> Vec<(u8, f64, usize)> {
+    (4..17).map(|b| {
+        let m = 1usize << b;
+        (b, typical_error_rate(m), m)
+    }).collect()
 }
 
-/// `HyperLogLog`オブジェクト
-pub struct HyperLogLog {
-    // レジスタのアドレッシングに使う2進数のビット数
-    // 範囲は4以上、16以下で、大きいほど見積もり誤差が少なくなるが、その分メモリを使用する。
-    b: u8,
-    // usizes型のハッシュ値の右からbビットを取り出すためのマスク
-    b_mask: usize,
-    // レジスタの数(2のb乗)。例: b = 4 → 16、b = 16 → 65536
-    m: usize,
-    alpha: f64,
-    // レジスタ。サイズが mバイトのバイト配列
-    registers: Vec<u8>,
-    // SipHasher の初期化に使うキー
-    hasher_key0: u64,
-    hasher_key1: u64,    
+/// bビットのスケッチのレジスタの配列registersから、`cardinality_with_method`と同じ推定を
+/// 行い、見積もり値と使用したアルゴリズムを返す。レジスタは64ビットのハッシュ値で
+/// 作られたものとみなす。要素の追加を経ずに、任意のレジスタの分布に対する推定を調べるために
+/// 使う。bが範囲外、registersの長さが2^bでない、または値が上限(65 - b)を超えれば`Err`を返す
+pub fn estimate_from_registers(b: u8, registers: &[u8]) -> Result<(f64, Estimator), Box<dyn Error>> {
+    let hll = HyperLogLog::from_parts(b, 0, 0, registers.to_vec())?;
+    Ok(hll.cardinality_with_method())
 }
 
-/// `HyperLogLog`のデバッグ用文字列を返す。
-impl fmt::Debug for HyperLogLog {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let (est, est_method) = estimate_cardinality(self);
-        write!(f,
-        r#"HyperLogLog
-  estimated cardinality: {}
-  estimation method:     {:?}
-  -----------------------------------------------------
-  b:      {} bits (typical error rate: {}%)
-  m:      {} registers
-  alpha:  {}
-  hasher: ({}, {})"#,
-               est,
-               est_method,
-               self.b,
-               self.typical_error_rate() * 100.0,
-               self.m,
-               self.alpha,
-               self.hasher_key0,
-               self.hasher_key1)
+/// itemsをbビットのスケッチに追加し、見積もり値の相対誤差が`typical_error_rate`の
+/// tolerance_multiplier倍を超えたらpanicする。真のカーディナリティは`HashSet`で求める。
+/// 見積もり精度の劣化を検出するためのテスト用の関数
+#[cfg(feature = "testing")]
+pub fn assert_within_error<H: Hash + Eq>(items: &[H], b: u8, tolerance_multiplier: f64) {
+    let mut hll = HyperLogLog::new(b).expect("b must be between 4 and 16");
+    let mut distinct = HashSet::new();
+    for x in items {
+        hll.insert(x);
+        distinct.insert(x);
     }
+    let error = hll.measured_error(distinct.len() as u64).abs();
+    let tolerance = tolerance_multiplier * hll.typical_error_rate();
+    assert!(error <= tolerance, "relative error {} exceeds {} (distinct = {}, estimate = {})",
+            error, tolerance, distinct.len(), hll.cardinality());
 }
 
-impl HyperLogLog {
+/// bビットのスケッチについて、生の`HyperLogLog`の見積もり値(`Linear Counting`も
+/// レンジ補正も行わない値)の偏りを実測し、(見積もり値の平均, 偏り)の組を返す。
+/// 偏りは見積もり値の平均から真のカーディナリティを引いたもの。真のカーディナリティは
+/// m / 8から5mまでm / 8刻みで、それぞれsamples個のキーの異なるスケッチで平均する。
+/// HyperLogLog++のような偏り補正の表を作り直したり、検証したりするための関数
+#[cfg(feature = "testing")]
+pub fn generate_bias_table(b: u8, samples: usize) -> Vec<(f64, f64)> {
+    assert!(samples > 0, "samples must be at least 1");
+    let template = HyperLogLog::new(b).expect("b must be between 4 and 16");
+    let m = template.m;
+    let step = m / 8;
+    let points = 40;
+    let mut sums = vec![0.0; points];
+    for sample in 0..samples {
+        let mut hll = template.empty_like();
+        let mut next = 0u64;
+        for (k, sum) in sums.iter_mut().enumerate() {
+            let cardinality = ((k + 1) * step) as u64;
+            while next < cardinality {
+                hll.insert(&(sample as u64, next));
+                next += 1;
+            }
+            let (register_sum, _) = sum_and_count_zero_registers(&hll.registers);
+            let raw: Float = raw_hyperloglog_estimate(hll.alpha, m as Float, register_sum);
+            *sum += raw as f64;
+        }
+    }
+    sums.iter().enumerate().map(|(k, &sum)| {
+        let mean = sum / samples as f64;
+        (mean, mean - ((k + 1) * step) as f64)
+    }).collect()
+}
 
-    /// `HyperLogLog`オブジェクトを作成する。bで指定したビット数をレジスタの
-    /// アドレッシングに使用する。bの範囲は4以上、16以下でなければならない
-    /// 範囲外なら`Err`を返す
-    pub fn new(b: u8) -> Result<Self, Box<Error>> {
-        if b < 4 || b > 16 {
-            return Err(From::from(format!("b must be between 4 and 16. b = {}", b)))
+/// 一定時間ごとの`HyperLogLog`オブジェクト(バケット)をリングバッファに保持し、
+/// 直近Nバケットのカーディナリティを見積もる。例: 1分ごとのスケッチから1時間の値を求める
+pub struct HllTimeSeries {
+    // 保持するバケットの最大数。超えると古いものから捨てる
+    capacity: usize,
+    buckets: VecDeque<HyperLogLog>,
+}
+
+impl HllTimeSeries {
+
+    /// 最大capacity個のバケットを保持する`HllTimeSeries`を作成する。
+    /// capacityが0なら`Err`を返す
+    pub fn new(capacity: usize) -> Result<Self, Box<dyn Error>> {
+        if capacity == 0 {
+            return Err(From::from("capacity must be at least 1"))
         }
-        /// 構造体のフィールド`m`は2のb条。シフト演算で実装
-        let m     = 1 << b;
-        let alpha = get_alpha(b)?;
-        // hasher_key0, key1を初期化するための乱数ジェネレータ
-        let mut rng = rand::OsRng::new().map_err(|e| format!("Failed to create an OS RNG: {}", e))?;
-
-        Ok(HyperLogLog {
-            alpha: alpha,
-            b: b,
-            b_mask: m - 1,
-            m: m,
-            registers: vec![0; m],
-            hasher_key0: rng.gen(),
-            hasher_key1: rng.gen(),            
+        Ok(HllTimeSeries {
+            capacity,
+            buckets: VecDeque::with_capacity(capacity),
         })
     }
 
-    /// 要素を追加する。要素は`std::hash::Hash`トレイトを実装していなければならない
-    pub fn insert<H: Hash>(&mut self, value: &H) {
-        let x = self.hash(value);
-        let j = x as usize & self.b_mask;
-        let w = x >> self.b;
-
-        let p1 = position_of_leftmost_one_bit(w, 64 - self.b);
-        let p2 = &mut self.registers[j];
-        if *p2 < p1 {
-            *p2 = p1;
+    /// 最新のバケットを追加する。すべてのバケットはbとハッシュのキーが
+    /// 一致していなければならず、一致しなければ`Err`を返す
+    pub fn add_bucket(&mut self, bucket: HyperLogLog) -> Result<(), Box<dyn Error>> {
+        if let Some(first) = self.buckets.front() {
+            first.check_mergeable(&bucket)?;
+        }
+        if self.buckets.len() == self.capacity {
+            self.buckets.pop_front();
         }
+        self.buckets.push_back(bucket);
+        Ok(())
     }
 
-    /// カーディナリティの見積もり値を返す
-    pub fn cardinality(&self) -> f64 {
-        estimate_cardinality(self).0
+    /// 保持しているバケットの数を返す
+    pub fn len(&self) -> usize {
+        self.buckets.len()
     }
 
-    /// b から予想される典型的なエラー率を返す
-    pub fn typical_error_rate(&self) -> f64 {
-        1.04 / (self.m as f64).sqrt()
+    /// バケットを一つも保持していなければ`true`を返す
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
     }
 
-    /// 与えられたvalueに対する64ビットのハッシュ値を求める。
-    #[allow(deprecated)] // SipHasherがRust1.13.0で非推奨(deprecated)のため
-    fn hash<H: Hash>(&self, value: &H) -> u64 {
-        let mut hasher = SipHasher::new_with_keys(self.hasher_key0, self.hasher_key1);
-        value.hash(&mut hasher);
-        hasher.finish()
+    /// 直近n_buckets個のバケットをマージし、そのカーディナリティの見積もり値を返す。
+    /// n_bucketsが0、または保持しているバケットの数より多ければ`Err`を返す
+    pub fn cardinality_over_last(&self, n_buckets: usize) -> Result<f64, Box<dyn Error>> {
+        if n_buckets == 0 || n_buckets > self.buckets.len() {
+            return Err(From::from(format!("n_buckets must be between 1 and {}. n_buckets = {}",
+                                          self.buckets.len(), n_buckets)))
+        }
+        let mut recent = self.buckets.iter().rev().take(n_buckets);
+        // 最新のバケットを複製し、残りをマージしていく
+        let mut union = recent.next().unwrap().clone();
+        for bucket in recent {
+            union.merge(bucket)?;
+        }
+        Ok(union.cardinality())
     }
+}
+
+/// 精度(b)を自動的に上げていく`HyperLogLog`。カーディナリティが事前に分からない場合に、
+/// 小さな精度から始めて、必要に応じてメモリを増やす。
+///
+/// 現在のスケッチの値が0のレジスタがm / 10を下回る(`Linear Counting`が使えなくなる)と、
+/// 同じキーでbが1大きい空のスケッチに切り替え、以降の要素はそちらに追加する。
+/// 要素を再追加することはできないため、切り替え前のスケッチはそのまま保持する。
+/// そのため、使用するメモリは最終的なbのスケッチのおよそ2倍になる。
+///
+/// 見積もり値は、現在のスケッチの見積もり値に、切り替え前の各スケッチにしか
+/// 含まれない要素の数を新しい順に足したもの。後者はそのスケッチのbに畳み込んだ
+/// 和集合との差として見積もるので、その誤差はそのスケッチのエラー率に従う
+pub struct AdaptiveHll {
+    // 切り替え前のスケッチ(bの小さい順)
+    retired: Vec<HyperLogLog>,
+    current: HyperLogLog,
+    // 現在のスケッチの値が0のレジスタの数
+    zeros: usize,
+    max_b: u8,
+}
 
-    /// レジスタに格納された値について、その分布を示すヒストグラムを返す。
-    pub fn histgram_of_register_value_distribution(&self) -> String {
-        let mut histgram = Vec::new();
+impl AdaptiveHll {
 
-        let mut map = BTreeMap::new();
-        for x in &self.registers {
-            let count = map.entry(*x).or_insert(0);
-            *count += 1;
+    /// bの範囲がinitial_b以上、max_b以下の`AdaptiveHll`を作成する。
+    /// initial_b、max_bが範囲外、またはinitial_bがmax_bより大きければ`Err`を返す
+    pub fn new(initial_b: u8, max_b: u8) -> Result<Self, Box<dyn Error>> {
+        AdaptiveHll::with_initial_sketch(max_b, || HyperLogLog::new(initial_b))
+    }
+
+    /// `new`と同様だが、ハッシュのキーを`HyperLogLog::with_seed`と同じ規則でseedから導く
+    pub fn with_seed(initial_b: u8, max_b: u8, seed: u64) -> Result<Self, Box<dyn Error>> {
+        AdaptiveHll::with_initial_sketch(max_b, || HyperLogLog::with_seed(initial_b, seed))
+    }
+
+    /// 最初のスケッチをnew_sketchで作成する。精度を上げたスケッチはキーを引き継ぐ
+    fn with_initial_sketch<F>(max_b: u8, new_sketch: F) -> Result<Self, Box<dyn Error>>
+        where F: FnOnce() -> Result<HyperLogLog, Box<dyn Error>> {
+        get_alpha(max_b)?;
+        let current = new_sketch()?;
+        if current.b > max_b {
+            return Err(From::from(format!("initial_b must be at most max_b. initial_b = {}, max_b = {}",
+                                          current.b, max_b)))
         }
+        Ok(AdaptiveHll {
+            retired: Vec::new(),
+            zeros: current.m,
+            current,
+            max_b,
+        })
+    }
 
-        if let (Some(last_reg_value), Some(max_count)) = (map.keys().last(), map.values().max()) {
-            // グラフの最大幅 = 40文字
-            let width = 40.0;
-            let rate  = width / (*max_count as f64);
-
-            for i 0..(last_reg_value + 1) {
-                let mut line = format!("{:3}: ", i);
-
-                if let Some(count) = map.get(&i) {
-                    // アスタリスク(*)で横棒を描く
-                    let h_bar = str::iter::repeat("*")
-                        .take((*count as f64 * rate).cell() as usize)
-                        .collect::<String>();
-                    line.push_str(&h_bar);
-                    line.push_str(&format!("  {}", count));                    
-                } else {
-                    line.push_str("0");
-                };
-
-                histgram.push(line);
-            }
+    /// 現在の精度(b)を返す
+    pub fn precision(&self) -> u8 {
+        self.current.b
+    }
+
+    /// 要素を追加する。必要なら、追加後にスケッチの精度を上げる
+    pub fn insert<H: Hash>(&mut self, value: &H) {
+        let x = self.current.hash(value);
+        if self.current.update_register(x).2 == 0 {
+            self.zeros -= 1;
+        }
+        if self.zeros < self.current.m / 10 && self.current.b < self.max_b {
+            self.upgrade();
         }
-        histgram.join("\n")
     }
-    
-}
 
+    /// 現在のスケッチを保持し、bが1大きい空のスケッチに切り替える
+    fn upgrade(&mut self) {
+        let next = self.current.empty_with_b(self.current.b + 1).expect("b is at most max_b");
+        let retired = std::mem::replace(&mut self.current, next);
+        self.retired.push(retired);
+        self.zeros = self.current.m;
+    }
 
-/// ビット数bに対応するα値を返す。
-fn get_alpha(b: u8) -> Result<f64, Box<Error>> {
-    if b < 4 || b > 16 {
-        Err(From::from(format!("b must be between 4 and 16. b = {}", b)))
-    } else {
-        Ok(match b {
-            4 => 0.673, // α16
-            5 => 0.697, // α32
-            6 => 0.709, // α64
-            _ => 0.7213 / (1.0 + 1.079 / (1 << b) as f64),
-        })
+    /// カーディナリティの見積もり値を返す
+    pub fn cardinality(&self) -> f64 {
+        let mut est = self.current.cardinality();
+        // 新しいスケッチから順に、それ以降のスケッチとの和集合を畳み込みながら作っていき、
+        // 各スケッチにしか含まれない要素の数を足す
+        let mut later = self.current.clone();
+        for stage in self.retired.iter().rev() {
+            let mut union = later.folded(stage.b);
+            let before = union.cardinality();
+            union.merge(stage).expect("keys are shared by all stages");
+            est += (union.cardinality() - before).max(0.0);
+            later = union;
+        }
+        est
     }
 }
 
-
-/// ハッシュ値(64ビット符号なしの2進数)の左端からみて最初に出現した1の位置を返す
-/// 例: 10000... -> 1、00010... -> 4
-fn position_of_leftmost_one_bit(s: u64, max_width: u8) -> u8 {
-    count_leading_zeros(s, max_width) + 1
+/// キーの異なるk個の`HyperLogLog`で同じ要素を数え、見積もり値の平均を返す。
+/// 各スケッチの見積もり誤差は独立なので、平均の典型的なエラー率は1つのスケッチの
+/// 1/√k倍になる。ただし、メモリと挿入の時間はk倍かかる。同じメモリならbを上げる方が
+/// 効率がよい(メモリ4倍でエラー率1/2)ので、b = 16でも精度が足りない場合に使う
+pub struct HllEnsemble {
+    sketches: Vec<HyperLogLog>,
 }
 
-/// ハッシュ値(64ビット符号なし2進数)左端に連続して並んでいる0の個数を返す.
-/// 10000... -> 0、00010... -> 3
-fn count_leading_zeros(mut s: u64, max_width: u8) -> u8 {
-    let mut lz = max_width;
-    while s != 0 {
-        lz -= 1;
-        s >>= 1;
+impl HllEnsemble {
+
+    /// bビットのスケッチをk個持つ`HllEnsemble`を作成する。各スケッチのキーは
+    /// ランダムに決める。bが範囲外、またはkが0なら`Err`を返す
+    pub fn new(b: u8, k: usize) -> Result<Self, Box<dyn Error>> {
+        if k == 0 {
+            return Err(From::from("k must be at least 1"))
+        }
+        let sketches = (0..k).map(|_| HyperLogLog::new(b)).collect::<Result<Vec<_>, _>>()?;
+        Ok(HllEnsemble { sketches })
     }
-    lz
-}
 
-/// カーディナリティを推定し、その値と見積もりに使用したアルゴリズムを返す
-/// スモールレンジでは`Linear Counting`アルゴリズムを使用し、それを超えるレンジでは
-/// `HyperLogLog`アルゴリズムを使用する。ここまでは論文の通り。
-/// しかし、論文にあるラーレンジ補正は行わない。なぜなら、本実装では、32ビットの
-/// ハッシュ値の代わりに64ビットのハッシュ値を使用しており、ハッシュ値が衝突する
-/// 頻度が極めて低いと予想されるため
-fn estimate_cardinality(hll: &HyperLogLog) -> (f64, Estimator) {
-    let m_64 = hll.m as f64;
-    // まず`HyperLogLog`アルゴリズムによる見積もり値を算出する
-    let est = raw_hyperloglog_estimate(hll.alpha, m_64, &hll.registers);
-
-    if est < (5.0 / 2.0 * m_64) {
-        // スモールレンジの見積もりを行う。もし値が0のレジスタが一つでもあるならば
-        // `Linear Counting`アルゴリズムで見積もりし直す。
-        match count_zero_registers(&hll.registers) {
-            0 => (est, Estimator::HyperLogLog),
-            v => (linear_counting_estimate(m_f64, v as f64), Estimator::LinerCounting),
+    /// 要素をすべてのスケッチに追加する
+    pub fn insert<H: Hash>(&mut self, value: &H) {
+        for sketch in &mut self.sketches {
+            sketch.insert(value);
         }
-    } else {
-        (est, Estimator::HyperLogLog)
+    }
+
+    /// 各スケッチの見積もり値の平均を返す
+    pub fn cardinality(&self) -> f64 {
+        self.sketches.iter().map(HyperLogLog::cardinality).sum::<f64>() / self.sketches.len() as f64
+    }
+
+    /// 平均の典型的なエラー率(1つのスケッチのエラー率 / √k)を返す
+    pub fn typical_error_rate(&self) -> f64 {
+        self.sketches[0].typical_error_rate() / (self.sketches.len() as f64).sqrt()
+    }
+
+    /// スケッチを返す
+    pub fn sketches(&self) -> &[HyperLogLog] {
+        &self.sketches
     }
 }
 
-/// 値が0のレジスタの個数を返す
-fn count_zero_registers(registers: &[u8]) -> usize {
-    registers.iter().filter(|&x| *x == 0).count()
+/// `HyperLogLog`に要素を書き込む`std::io::Write`の実装。`io::copy`などで
+/// ストリームの異なる要素の数を数えるために使う。
+///
+/// `new`で作成すると`write`に渡された各バッファを1つの要素として追加する。
+/// バッファの区切りは呼び出し側に依存するので、`io::copy`で使う場合は`lines`を使うこと。
+/// 要素は`insert(&chunk)`(chunkは`&[u8]`)と同じハッシュ値で追加する
+pub struct HllWriter {
+    hll: HyperLogLog,
+    // 改行区切りモードならtrue
+    split_lines: bool,
+    // 改行区切りモードで、まだ改行が現れていない行の途中
+    pending: Vec<u8>,
 }
 
-/// `HyperLogLog`アルゴリズムによる未補正の見積もり値を計算する
-fn raw_hyperloglog_estimate(alpha: f64, m: f64, registers: &[u8]) -> f64 {
-    let sum = registers.iter().map(|&x| 2.0f64.powi(-(x as i32))).sum::<f64>();
-    alpha * m * m / sum
+impl HllWriter {
+
+    /// `write`の各バッファを1つの要素として追加する`HllWriter`を作成する
+    pub fn new(hll: HyperLogLog) -> Self {
+        HllWriter { hll, split_lines: false, pending: Vec::new() }
+    }
+
+    /// 改行(`\n`)で区切った各行を1つの要素として追加する`HllWriter`を作成する。
+    /// 改行そのものは要素に含まない
+    pub fn lines(hll: HyperLogLog) -> Self {
+        HllWriter { hll, split_lines: true, pending: Vec::new() }
+    }
+
+    /// 書き込みを終えてスケッチを返す。改行で終わっていない最後の行があれば、それも追加する
+    pub fn into_inner(mut self) -> HyperLogLog {
+        if !self.pending.is_empty() {
+            let line: &[u8] = &self.pending;
+            self.hll.insert(&line);
+        }
+        self.hll
+    }
 }
 
-/// `Linear Counting`アルゴリズムによる見積もり値を算出する
-fn linear_counting_estimate(m: f64, number_of_zero_registers: f64) -> f64 {
-    m * (m / number_of_zero_registers).ln()
+impl io::Write for HllWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.split_lines {
+            self.hll.insert(&buf);
+            return Ok(buf.len())
+        }
+        let mut rest = buf;
+        while let Some(pos) = rest.iter().position(|&c| c == b'\n') {
+            if self.pending.is_empty() {
+                let line = &rest[..pos];
+                self.hll.insert(&line);
+            } else {
+                self.pending.extend_from_slice(&rest[..pos]);
+                let line: &[u8] = &self.pending;
+                self.hll.insert(&line);
+                self.pending.clear();
+            }
+            rest = &rest[pos + 1..];
+        }
+        self.pending.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 公開している型が`Send + Sync`であることをコンパイル時に保証する。
+/// `Rc`や`Cell`のようなフィールドを追加してこれが崩れると、コンパイルエラーになる
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn assert<T: Send + Sync>() {}
+    assert::<HyperLogLog>();
+    assert::<HllTimeSeries>();
+    assert::<AdaptiveHll>();
+    assert::<HllEnsemble>();
+    assert::<HllWriter>();
+    assert::<HyperLogLogView>();
+    assert::<PackedHyperLogLog>();
 }
 
 // テストケース
@@ -231,34 +384,215 @@ mod tests {
     use super::*;
 
     #[test]
-    fn create_hll() {
-        
-        use std::f64;
-        
-        assert!(HyperLogLog::new(3).is_err());
-        assert!(HyperLogLog::new(17).is_err());
+    fn precision_for_memory() {
+        assert_eq!(super::precision_for_memory(8 * 1024).unwrap(), 13);
+        assert_eq!(super::precision_for_memory(8 * 1024 - 1).unwrap(), 12);
+        assert_eq!(super::precision_for_memory(1 << 20).unwrap(), 16);
+        assert_eq!(super::precision_for_memory(16).unwrap(), 4);
+        assert!(super::precision_for_memory(15).is_err());
+
+        // PackedHyperLogLogでは同じバイト数に2倍のレジスタが収まる
+        let b = super::precision_for_memory(4 * 1024 * 2).unwrap();
+        assert_eq!(PackedHyperLogLog::new(b).unwrap().register_bytes(), 4 * 1024);
+    }
+
+    #[test]
+    fn precision_options() {
+        let options = super::precision_options();
+        assert_eq!(options.len(), 13);
+        assert_eq!(options[0].0, 4);
+        let &(b, error, bytes) = options.iter().find(|o| o.0 == 10).unwrap();
+        let hll = HyperLogLog::new(b).unwrap();
+        assert_eq!(error, hll.typical_error_rate());
+        assert_eq!(bytes, 1024);
+        assert_eq!(super::precision_for_memory(bytes).unwrap(), b);
+    }
+
+    #[test]
+    fn estimate_from_registers() {
+        let (est, method) = super::estimate_from_registers(4, &[0; 16]).unwrap();
+        assert_eq!((est, method), (0.0, Estimator::LinerCounting));
+
+        let mut registers = vec![0; 16];
+        registers[3] = 2;
+        let (est, method) = super::estimate_from_registers(4, &registers).unwrap();
+        assert_eq!(method, Estimator::LinerCounting);
+        assert!((est - 16.0 * (16.0f64 / 15.0).ln()).abs() < 1e-4);
+
+        let (_, method) = super::estimate_from_registers(4, &[10; 16]).unwrap();
+        assert_eq!(method, Estimator::HyperLogLog);
+        let (est, method) = super::estimate_from_registers(4, &[61; 16]).unwrap();
+        assert_eq!(method, Estimator::Saturated);
+        assert!(est.is_finite());
+
+        let mut hll = HyperLogLog::new(10).unwrap();
+        for i in 0..5000 {
+            hll.insert(&i);
+        }
+        assert_eq!(super::estimate_from_registers(10, &hll.registers).unwrap(), hll.cardinality_with_method());
+
+        assert!(super::estimate_from_registers(3, &[0; 8]).is_err());
+        assert!(super::estimate_from_registers(4, &[0; 15]).is_err());
+        assert!(super::estimate_from_registers(4, &[62; 16]).is_err());
+    }
+
+    // b = 4ではm = 16、切り替え点の5m/2は40、α = 0.673で、
+    // 未補正の見積もり値は0.673 * 256 / Σ2^(-レジスタの値)
+    #[test]
+    fn estimate_from_registers_branches() {
+        let raw = |sum: f64| 0.673 * 256.0 / sum;
+        let close = |a: f64, b: f64| (a - b).abs() < b * 1e-5;
+
+        // すべて0: Linear Counting
+        assert_eq!(super::estimate_from_registers(4, &[0; 16]).unwrap(), (0.0, Estimator::LinerCounting));
+
+        // 5m/2の直下で値が0のレジスタがない: HyperLogLogのまま(Σ = 14/4 + 2/2 = 4.5)
+        let mut registers = [2; 16];
+        registers[0] = 1;
+        registers[1] = 1;
+        let (est, method) = super::estimate_from_registers(4, &registers).unwrap();
+        assert_eq!(method, Estimator::HyperLogLog);
+        assert!(est < 40.0 && close(est, raw(4.5)), "{}", est);
+
+        // 5m/2の直下で値が0のレジスタがある: Linear Counting(Σ = 1 + 15/4 = 4.75)
+        let mut registers = [2; 16];
+        registers[0] = 0;
+        assert!(raw(4.75) < 40.0);
+        let (est, method) = super::estimate_from_registers(4, &registers).unwrap();
+        assert_eq!(method, Estimator::LinerCounting);
+        assert!(close(est, 16.0 * 16.0f64.ln()), "{}", est);
+
+        // 5m/2を超える: 値が0のレジスタがあってもHyperLogLog(Σ = 1 + 15/32)
+        let mut registers = [5; 16];
+        registers[0] = 0;
+        let (est, method) = super::estimate_from_registers(4, &registers).unwrap();
+        assert_eq!(method, Estimator::HyperLogLog);
+        assert!(est > 40.0 && close(est, raw(1.0 + 15.0 / 32.0)), "{}", est);
+
+        // 5m/2を超える: Σ = 16/8 = 2
+        let (est, method) = super::estimate_from_registers(4, &[3; 16]).unwrap();
+        assert_eq!(method, Estimator::HyperLogLog);
+        assert!(est.is_finite() && close(est, raw(2.0)), "{}", est);
+    }
 
-        let hll = HyperLogLog::new(4);
-        assert!(hll.is_ok());
+    #[cfg(feature = "testing")]
+    #[test]
+    fn assert_within_error() {
+        let items = (0..50000).chain(0..10000).collect::<Vec<_>>();
+        super::assert_within_error(&items, 12, 4.0);
+    }
 
-        let hll = hll.unwrap();
-        assert_eq!(hll.b, 4);
-        assert_eq!(hll.m, 2_f64.powi(4) as usize);
-        assert_eq!(hll.alpha, 0.673);
-        assert_eq!(hll.registers.len(), 2_f64.powi(4) as usize);
+    #[cfg(feature = "testing")]
+    #[test]
+    #[should_panic(expected = "exceeds")]
+    fn assert_within_error_panics() {
+        super::assert_within_error(&(0..50000).collect::<Vec<_>>(), 12, 0.0);
+    }
 
-        assert!(HyperLogLog::new(16).is_ok());
+    #[cfg(feature = "testing")]
+    #[test]
+    fn generate_bias_table() {
+        let table = super::generate_bias_table(8, 20);
+        assert_eq!(table.len(), 40);
+        // 小さなカーディナリティでは生の見積もり値は大きく過大評価になり、
+        // 5m付近ではほぼ偏りがなくなる
+        let (first_raw, first_bias) = table[0];
+        assert!(first_bias > 0.0 && first_raw > 32.0, "{:?}", table[0]);
+        let (last_raw, last_bias) = table[39];
+        assert!(last_bias.abs() < last_raw * 0.05, "{:?}", table[39]);
     }
 
     #[test]
-    fn small_range() {
-        let mut hll = HyperLogLog::new(12).unwrap();
-        let items = ["test1", "test2", "test3", "test2", "test2", "test2"];
+    fn adaptive() {
+        assert!(AdaptiveHll::new(4, 17).is_err());
+        assert!(AdaptiveHll::new(8, 6).is_err());
+
+        assert!(AdaptiveHll::with_seed(8, 6, 1).is_err());
+
+        // 精度の検査を再現できるように、キーを固定する
+        let mut hll = AdaptiveHll::with_seed(4, 12, 1).unwrap();
+        assert_eq!(hll.precision(), 4);
+        for i in 0..100000 {
+            hll.insert(&i);
+        }
+        assert_eq!(hll.precision(), 12);
+        // 最終的な精度のエラー率に近い精度で見積もれる
+        let est = hll.cardinality();
+        assert!((est - 100000.0).abs() < 100000.0 * 0.1, "{}", est);
+    }
+
+    #[test]
+    fn ensemble() {
+        assert!(HllEnsemble::new(10, 0).is_err());
+        assert!(HllEnsemble::new(3, 4).is_err());
+
+        let mut ensemble = HllEnsemble::new(8, 16).unwrap();
+        assert_eq!(ensemble.typical_error_rate(), ensemble.sketches()[0].typical_error_rate() / 4.0);
+        for i in 0..50000 {
+            ensemble.insert(&i);
+        }
+        let sketches = ensemble.sketches();
+        assert!(sketches[1..].iter().all(|s| s.keys() != sketches[0].keys()));
+        let mean = sketches.iter().map(HyperLogLog::cardinality).sum::<f64>() / 16.0;
+        assert_eq!(ensemble.cardinality(), mean);
+        let error = (ensemble.cardinality() - 50000.0).abs() / 50000.0;
+        assert!(error < 4.0 * ensemble.typical_error_rate(), "{}", error);
+    }
+
+    #[test]
+    fn time_series() {
+        assert!(HllTimeSeries::new(0).is_err());
+
+        let template = HyperLogLog::new(10).unwrap();
+        let mut series = HllTimeSeries::new(3).unwrap();
+        for minute in 0..4 {
+            let mut bucket = template.clone();
+            for i in (minute * 100)..(minute * 100 + 150) {
+                bucket.insert(&i);
+            }
+            series.add_bucket(bucket).unwrap();
+        }
+        // 最も古いバケットは捨てられている
+        assert_eq!(series.len(), 3);
+
+        let last_one = series.cardinality_over_last(1).unwrap();
+        let last_three = series.cardinality_over_last(3).unwrap();
+        assert!(last_one < last_three);
+        assert!((last_three - 350.0).abs() < 350.0 * 0.1);
+
+        assert!(series.cardinality_over_last(0).is_err());
+        assert!(series.cardinality_over_last(4).is_err());
+        assert!(series.add_bucket(HyperLogLog::new(10).unwrap()).is_err());
+    }
+
+    #[test]
+    fn writer() {
+        use std::io::Write;
+
+        let template = HyperLogLog::new(10).unwrap();
+        let text = b"apple\nbanana\napple\ncherry";
+        let mut expected = template.clone();
+        for line in text.split(|&c| c == b'\n') {
+            expected.insert(&line);
+        }
+
+        let mut writer = HllWriter::lines(template.clone());
+        io::copy(&mut &text[..], &mut writer).unwrap();
+        assert_eq!(writer.into_inner().registers, expected.registers);
 
-        println!("\n=== Loading {} items.\n", items.len());
-        for item in &items {
-            hll.insert(item);
+        // 行の途中でバッファが区切られても結果は変わらない
+        let mut writer = HllWriter::lines(template.clone());
+        for chunk in text.chunks(3) {
+            writer.write_all(chunk).unwrap();
         }
-        
+        assert_eq!(writer.into_inner().registers, expected.registers);
+
+        let mut writer = HllWriter::new(template.clone());
+        writer.write_all(b"apple").unwrap();
+        writer.write_all(b"banana").unwrap();
+        let mut expected = template.clone();
+        expected.insert(&&b"apple"[..]);
+        expected.insert(&&b"banana"[..]);
+        assert_eq!(writer.into_inner().registers, expected.registers);
     }
 }
@@ -0,0 +1,216 @@
+//! レジスタを4ビットずつ詰めて格納する、メモリの少ない`HyperLogLog`
+
+use std::error::Error;
+use std::hash::Hash;
+
+use estimate::{Float, get_alpha, estimate_cardinality_of_values};
+use hash::{HashAlgorithm, HashWidth, hash_value, position_of_leftmost_one_bit};
+use sketch::{HyperLogLog, os_random_keys};
+
+/// `PackedHyperLogLog`のレジスタの値の上限。4ビットで表せる最大値
+pub const PACKED_REGISTER_CAP: u8 = 15;
+
+/// レジスタを4ビットずつ詰めて格納する`HyperLogLog`。レジスタ配列のメモリは
+/// `HyperLogLog`の半分(m / 2バイト)になる。メモリの限られた環境で、小さなカーディナリティを
+/// 数えるためのもの。ハッシュはSipHash-2-4の64ビットのハッシュ値を使う。
+///
+/// レジスタの値は`PACKED_REGISTER_CAP`(15)で頭打ちになる。これは
+/// `HyperLogLog::with_register_cap(b, 15)`と同じで、カーディナリティがおよそ
+/// m * 2^15に近づくと見積もり値は過小評価になる。見積もり値は区別できる上限
+/// `capacity`(m * 2^14)を超えない値を返し、すべてのレジスタが上限に達すると
+/// `is_saturated`が`true`になる
+#[derive(Clone)]
+pub struct PackedHyperLogLog {
+    b: u8,
+    alpha: Float,
+    hasher_key0: u64,
+    hasher_key1: u64,
+    // 1バイトに2つのレジスタを格納する。下位4ビットが偶数番目、上位4ビットが奇数番目
+    nibbles: Vec<u8>,
+}
+
+impl PackedHyperLogLog {
+
+    /// `PackedHyperLogLog`オブジェクトを作成する。ハッシュのキーはランダムに決める。
+    /// bの範囲は4以上、16以下でなければならず、範囲外なら`Err`を返す
+    pub fn new(b: u8) -> Result<Self, Box<dyn Error>> {
+        let alpha = get_alpha(b)?;
+        let (hasher_key0, hasher_key1) = os_random_keys()?;
+        Ok(PackedHyperLogLog { b, alpha, hasher_key0, hasher_key1, nibbles: vec![0; 1 << (b - 1)] })
+    }
+
+    /// `HyperLogLog`のレジスタを詰めて格納した`PackedHyperLogLog`を作成する。
+    /// 上限を超えるレジスタの値は`PACKED_REGISTER_CAP`に切り詰める。
+    /// hllがSipHash-2-4の64ビットのハッシュ値を使っていなければ`Err`を返す
+    pub fn from_hyperloglog(hll: &HyperLogLog) -> Result<Self, Box<dyn Error>> {
+        if hll.hash_algorithm() != HashAlgorithm::SipHash24 || hll.hash_width != HashWidth::Bits64 {
+            return Err(From::from("only sketches using 64-bit SipHash-2-4 hashes can be packed"))
+        }
+        let (hasher_key0, hasher_key1) = hll.keys();
+        let mut packed = PackedHyperLogLog {
+            b: hll.b,
+            alpha: hll.alpha,
+            hasher_key0,
+            hasher_key1,
+            nibbles: vec![0; hll.m / 2],
+        };
+        for (j, &x) in hll.registers.iter().enumerate() {
+            packed.set_register(j, x.min(PACKED_REGISTER_CAP));
+        }
+        Ok(packed)
+    }
+
+    /// `from_hyperloglog`と同様に作成するが、値が`PACKED_REGISTER_CAP`を超えるレジスタが
+    /// あれば切り詰めずに`Err`を返す。情報を失わずに詰めて格納できる場合だけ変換するために使う
+    pub fn from_hyperloglog_strict(hll: &HyperLogLog) -> Result<Self, Box<dyn Error>> {
+        if let Some(j) = hll.registers.iter().position(|&x| x > PACKED_REGISTER_CAP) {
+            return Err(From::from(format!("registers[{}] = {} exceeds the packed maximum value {}",
+                                          j, hll.registers[j], PACKED_REGISTER_CAP)))
+        }
+        PackedHyperLogLog::from_hyperloglog(hll)
+    }
+
+    /// レジスタの値の上限を`PACKED_REGISTER_CAP`にした`HyperLogLog`に展開する。
+    /// 返すスケッチは、同じキーで`HyperLogLog::with_register_cap(b, 15)`を使って
+    /// 同じ要素を追加したものと同じになる
+    pub fn to_hyperloglog(&self) -> HyperLogLog {
+        let registers = (0..self.m()).map(|j| self.register(j)).collect();
+        let mut hll = HyperLogLog::from_parts(self.b, self.hasher_key0, self.hasher_key1, registers)
+            .expect("b and register values are already validated");
+        hll.register_cap = PACKED_REGISTER_CAP;
+        hll
+    }
+
+    /// 精度(b)を返す
+    pub fn precision(&self) -> u8 {
+        self.b
+    }
+
+    /// ハッシュの初期化に使うキーの組を返す
+    pub fn keys(&self) -> (u64, u64) {
+        (self.hasher_key0, self.hasher_key1)
+    }
+
+    /// レジスタ配列が使うバイト数(m / 2)を返す
+    pub fn register_bytes(&self) -> usize {
+        self.nibbles.len()
+    }
+
+    /// 要素を追加する。レジスタの値は`PACKED_REGISTER_CAP`で頭打ちになる
+    pub fn insert<H: Hash>(&mut self, value: &H) {
+        let x = hash_value(HashAlgorithm::SipHash24, self.hasher_key0, self.hasher_key1, value);
+        let j = x as usize & (self.m() - 1);
+        let p = position_of_leftmost_one_bit(x >> self.b, 64 - self.b).min(PACKED_REGISTER_CAP);
+        if self.register(j) < p {
+            self.set_register(j, p);
+        }
+    }
+
+    /// カーディナリティの見積もり値を返す。`capacity`を超えない
+    pub fn cardinality(&self) -> f64 {
+        let values = (0..self.m()).map(|j| self.register(j));
+        estimate_cardinality_of_values(self.alpha, self.m(), values).0.min(self.capacity())
+    }
+
+    /// レジスタの値の上限から区別できるカーディナリティの上限m * 2^(15 - 1)を返す
+    pub fn capacity(&self) -> f64 {
+        self.m() as f64 * (1u32 << (PACKED_REGISTER_CAP - 1)) as f64
+    }
+
+    /// すべてのレジスタが上限に達していて、これ以上要素を追加しても変化しなければ`true`を返す
+    pub fn is_saturated(&self) -> bool {
+        self.nibbles.iter().all(|&x| x == 0xff)
+    }
+
+    /// otherをマージする。bとハッシュのキーが一致しなければ`Err`を返す
+    pub fn merge(&mut self, other: &PackedHyperLogLog) -> Result<(), Box<dyn Error>> {
+        if self.b != other.b {
+            return Err(From::from(format!("b must be the same. self.b = {}, other.b = {}", self.b, other.b)))
+        }
+        if self.keys() != other.keys() {
+            return Err(From::from("hasher keys must be the same"))
+        }
+        for (x, &y) in self.nibbles.iter_mut().zip(&other.nibbles) {
+            *x = (*x & 0x0f).max(y & 0x0f) | (*x & 0xf0).max(y & 0xf0);
+        }
+        Ok(())
+    }
+
+    fn m(&self) -> usize {
+        self.nibbles.len() * 2
+    }
+
+    /// j番目のレジスタの値を返す
+    fn register(&self, j: usize) -> u8 {
+        let byte = self.nibbles[j / 2];
+        if j.is_multiple_of(2) { byte & 0x0f } else { byte >> 4 }
+    }
+
+    /// j番目のレジスタに値xを書き込む。xは`PACKED_REGISTER_CAP`以下でなければならない
+    fn set_register(&mut self, j: usize, x: u8) {
+        let byte = &mut self.nibbles[j / 2];
+        *byte = if j.is_multiple_of(2) { (*byte & 0xf0) | x } else { (*byte & 0x0f) | (x << 4) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn matches_capped_sketch() {
+        // 精度の検査を再現できるように、キーを固定する
+        let mut packed = PackedHyperLogLog::from_hyperloglog(&HyperLogLog::with_seed(6, 1).unwrap()).unwrap();
+        assert_eq!(packed.register_bytes(), 32);
+        let mut capped = HyperLogLog::with_register_cap(6, PACKED_REGISTER_CAP).unwrap();
+        let (key0, key1) = packed.keys();
+        capped.set_keys(key0, key1).unwrap();
+        for i in 0..2000 {
+            packed.insert(&i);
+            capped.insert(&i);
+        }
+        let unpacked = packed.to_hyperloglog();
+        assert_eq!(unpacked.registers, capped.registers);
+        assert_eq!(packed.cardinality(), capped.cardinality());
+        assert!((packed.cardinality() - 2000.0).abs() < 2000.0 * 0.4, "{}", packed.cardinality());
+
+        let repacked = PackedHyperLogLog::from_hyperloglog_strict(&unpacked).unwrap();
+        assert_eq!(repacked.nibbles, packed.nibbles);
+        assert!(PackedHyperLogLog::from_hyperloglog(&HyperLogLog::with_siphash13(6).unwrap()).is_err());
+        assert!(PackedHyperLogLog::new(3).is_err());
+    }
+
+    #[test]
+    fn clamps_at_cap() {
+        let mut hll = HyperLogLog::new(4).unwrap();
+        for j in 0..16 {
+            hll.set_register(j, 40).unwrap();
+        }
+        let err = PackedHyperLogLog::from_hyperloglog_strict(&hll).err().unwrap().to_string();
+        assert!(err.contains("registers[0] = 40"), "{}", err);
+        let packed = PackedHyperLogLog::from_hyperloglog(&hll).unwrap();
+        assert!(packed.is_saturated());
+        assert!(packed.to_hyperloglog().registers.iter().all(|&x| x == PACKED_REGISTER_CAP));
+        assert_eq!(packed.cardinality(), packed.capacity());
+        assert_eq!(packed.capacity(), 16.0 * 16384.0);
+    }
+
+    #[test]
+    fn merge() {
+        let empty = PackedHyperLogLog::new(8).unwrap();
+        let mut a = empty.clone();
+        let mut b = empty.clone();
+        let mut union = empty.clone();
+        for i in 0..500 {
+            a.insert(&i);
+            b.insert(&(i + 250));
+            union.insert(&i);
+            union.insert(&(i + 250));
+        }
+        a.merge(&b).unwrap();
+        assert_eq!(a.nibbles, union.nibbles);
+        assert!(a.merge(&PackedHyperLogLog::new(8).unwrap()).is_err());
+        assert!(a.merge(&PackedHyperLogLog::new(9).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+//! SipHash-1-3の最小限の実装。標準ライブラリのSipHasher13は非安定(unstable)なため、
+//! ここで実装する。アルゴリズムは https://131002.net/siphash/ を参照
+
+use std::hash::Hasher;
+
+/// SipHash-1-3(圧縮ラウンド1回、終了ラウンド3回)
+#[derive(Debug, Clone)]
+pub struct SipHasher13 {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+    // 8バイトに満たない未処理の入力
+    tail: u64,
+    // tailに入っているバイト数
+    ntail: usize,
+    // これまでに入力された総バイト数
+    length: usize,
+}
+
+impl SipHasher13 {
+
+    /// 128ビットのキー(key0, key1)で初期化した`SipHasher13`を作成する
+    pub fn new_with_keys(key0: u64, key1: u64) -> SipHasher13 {
+        SipHasher13 {
+            v0: key0 ^ 0x736f_6d65_7073_6575,
+            v1: key1 ^ 0x646f_7261_6e64_6f6d,
+            v2: key0 ^ 0x6c79_6765_6e65_7261,
+            v3: key1 ^ 0x7465_6462_7974_6573,
+            tail: 0,
+            ntail: 0,
+            length: 0,
+        }
+    }
+
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13);
+        self.v1 ^= self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16);
+        self.v3 ^= self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21);
+        self.v3 ^= self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17);
+        self.v1 ^= self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    /// 8バイトのブロックを一つ圧縮する
+    fn compress(&mut self, block: u64) {
+        self.v3 ^= block;
+        self.round();
+        self.v0 ^= block;
+    }
+}
+
+/// バイト列をリトルエンディアンのu64として読む。bytesは8バイト以下
+fn read_le(bytes: &[u8]) -> u64 {
+    bytes.iter().enumerate().fold(0, |acc, (i, &x)| acc | (x as u64) << (8 * i))
+}
+
+impl Hasher for SipHasher13 {
+
+    fn write(&mut self, msg: &[u8]) {
+        self.length += msg.len();
+        let mut msg = msg;
+
+        // 前回の残りがあれば、まず8バイトになるまで埋める
+        if self.ntail != 0 {
+            let needed = 8 - self.ntail;
+            let fill = needed.min(msg.len());
+            self.tail |= read_le(&msg[..fill]) << (8 * self.ntail);
+            if msg.len() < needed {
+                self.ntail += msg.len();
+                return
+            }
+            let tail = self.tail;
+            self.compress(tail);
+            self.tail = 0;
+            self.ntail = 0;
+            msg = &msg[fill..];
+        }
+
+        let blocks = msg.chunks_exact(8);
+        let last = blocks.remainder();
+        for block in blocks {
+            self.compress(read_le(block));
+        }
+        self.tail = read_le(last);
+        self.ntail = last.len();
+    }
+
+    fn finish(&self) -> u64 {
+        let mut state = self.clone();
+        let block = ((self.length as u64 & 0xff) << 56) | self.tail;
+        state.compress(block);
+        state.v2 ^= 0xff;
+        state.round();
+        state.round();
+        state.round();
+        state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // SipHashの参照実装のテストベクトルと同じ条件(キーは0x00..0x0f、メッセージは0x00から
+    // 始まる連番)で求めたSipHash-1-3の値
+    const KEY0: u64 = 0x0706_0504_0302_0100;
+    const KEY1: u64 = 0x0f0e_0d0c_0b0a_0908;
+    const VECTORS: [(usize, u64); 7] = [
+        (0, 0xabac_0158_050f_c4dc),
+        (1, 0xc9f4_9bf3_7d57_ca93),
+        (7, 0xd392_7d98_9bb1_1140),
+        (8, 0x3690_9511_8d29_9a8e),
+        (15, 0xd320_d86d_2a51_9956),
+        (16, 0xcc4f_dd1a_7d90_8b66),
+        (63, 0x9d19_9062_b7bb_b3a8),
+    ];
+
+    #[test]
+    fn reference_vectors() {
+        let data = (0..64).collect::<Vec<u8>>();
+        for &(len, expected) in &VECTORS {
+            let mut hasher = SipHasher13::new_with_keys(KEY0, KEY1);
+            hasher.write(&data[..len]);
+            assert_eq!(hasher.finish(), expected, "len = {}", len);
+        }
+
+        // 分割して入力しても結果は同じ
+        let mut hasher = SipHasher13::new_with_keys(KEY0, KEY1);
+        hasher.write(&data[..3]);
+        hasher.write(&data[3..30]);
+        hasher.write(&data[30..63]);
+        assert_eq!(hasher.finish(), 0x9d19_9062_b7bb_b3a8);
+    }
+}
@@ -0,0 +1,3196 @@
+//! `HyperLogLog`本体。要素の追加、見積もり、マージ、シリアライズを行う
+
+use rand::{self, Rng};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use base64;
+use crc32::{self, Crc32};
+use estimate::{Estimator, Float, get_alpha, estimate_cardinality, estimate_cardinality_with_alpha,
+               all_estimates,
+               estimate_cardinality_of_registers,
+               sum_and_count_zero_registers, raw_hyperloglog_estimate, large_range_correction,
+               linear_counting_error, typical_error_rate};
+use hash::{HashAlgorithm, HashWidth, KeySource, hash_value, fixed_key_hasher, keys_from_seed,
+           max_register_value, position_of_leftmost_one_bit};
+
+/// シリアライズ形式のバージョン。先頭の1バイトに書き込む。
+/// 5はすべてのレジスタを、6は値が0でないレジスタだけを書き込み、末尾にチェックサムを付ける。
+/// 3と4はそれぞれのチェックサムを含まない旧形式、1と2はさらにキーの由来も含まない
+/// 旧形式で、どちらも読み込みだけに対応する
+const FORMAT_DENSE: u8 = 5;
+const FORMAT_SPARSE: u8 = 6;
+const FORMAT_DENSE_V2: u8 = 3;
+const FORMAT_SPARSE_V2: u8 = 4;
+const FORMAT_DENSE_V1: u8 = 1;
+const FORMAT_SPARSE_V1: u8 = 2;
+/// 旧形式のヘッダーの長さ。バージョン、b、ハッシュのアルゴリズム、ビット数、
+/// レジスタの値の上限(各1バイト)と、ハッシュのキー(各8バイト、リトルエンディアン)
+const HEADER_V1_LEN: usize = 21;
+/// シリアライズ形式のヘッダーの長さ。旧形式のヘッダーに続けて、キーの由来の識別子
+/// (1バイト)とシード(8バイト、リトルエンディアン。`Seed`以外では0)を書き込む
+const HEADER_LEN: usize = HEADER_V1_LEN + 9;
+/// 末尾のチェックサムの長さ。ヘッダーから本体の末尾までのCRC-32(リトルエンディアン)
+const CHECKSUM_LEN: usize = 4;
+
+/// 見積もり値のキャッシュが無効であることを示す値(NaNのビット表現の一つ)
+const CACHE_INVALID: u64 = u64::MAX;
+
+/// `compatibility_report`が返す、一つのスケッチについての検査結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SketchCompatibility {
+    /// bが先頭のスケッチと一致するか
+    pub b_matches: bool,
+    /// ハッシュのキーが先頭のスケッチと一致するか
+    pub keys_match: bool,
+    /// ハッシュのアルゴリズムとビット数が先頭のスケッチと一致するか
+    pub hash_matches: bool,
+}
+
+impl SketchCompatibility {
+
+    /// 先頭のスケッチとマージできるなら`true`を返す
+    pub fn is_compatible(&self) -> bool {
+        self.b_matches && self.keys_match && self.hash_matches
+    }
+}
+
+/// 複数のスケッチを先頭のスケッチと比べた、マージ可能性の検査結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatibilityReport {
+    /// 各スケッチの検査結果。インデックスは入力のスケッチと対応する
+    pub sketches: Vec<SketchCompatibility>,
+}
+
+impl CompatibilityReport {
+
+    /// 先頭のスケッチとマージできるスケッチのインデックスを返す
+    pub fn compatible_indices(&self) -> Vec<usize> {
+        (0..self.sketches.len()).filter(|&i| self.sketches[i].is_compatible()).collect()
+    }
+
+    /// 先頭のスケッチとマージできないスケッチのインデックスを返す
+    pub fn incompatible_indices(&self) -> Vec<usize> {
+        (0..self.sketches.len()).filter(|&i| !self.sketches[i].is_compatible()).collect()
+    }
+
+    /// すべてのスケッチが互いにマージできるなら`true`を返す
+    pub fn all_compatible(&self) -> bool {
+        self.sketches.iter().all(SketchCompatibility::is_compatible)
+    }
+}
+
+/// `HyperLogLog`オブジェクト。スレッド間で移動・共有できる(`Send + Sync`)
+pub struct HyperLogLog {
+    // レジスタのアドレッシングに使う2進数のビット数
+    // 範囲は4以上、16以下で、大きいほど見積もり誤差が少なくなるが、その分メモリを使用する。
+    pub(crate) b: u8,
+    // usizes型のハッシュ値の右からbビットを取り出すためのマスク
+    b_mask: usize,
+    // レジスタの数(2のb乗)。例: b = 4 → 16、b = 16 → 65536
+    pub(crate) m: usize,
+    pub(crate) alpha: Float,
+    // レジスタ。サイズが mバイトのバイト配列
+    pub(crate) registers: Vec<u8>,
+    // SipHasher の初期化に使うキー
+    hasher_key0: u64,
+    hasher_key1: u64,
+    hash_algorithm: HashAlgorithm,
+    pub(crate) hash_width: HashWidth,
+    // キーの由来。見積もりには使わない
+    key_source: KeySource,
+    // レジスタの値の上限。通常はレジスタが取りうる最大値(65 - b)
+    pub(crate) register_cap: u8,
+    // `cardinality`の見積もり値(f64のビット表現)のキャッシュ。
+    // レジスタが変化したら`CACHE_INVALID`に戻す。`Sync`を保つためアトミック型を使う
+    cached_estimate: AtomicU64,
+    // `cardinality`の見積もり値を`capacity`で頭打ちにするか
+    clamp_to_capacity: bool,
+    // `cardinality`の見積もり値をキャッシュするか
+    caching: bool,
+    // 各レジスタが最後に値を増やした挿入の通し番号。`enable_recency_tracking`で有効になる
+    register_stamps: Option<Vec<u64>>,
+    // 次の挿入に振る通し番号
+    insert_seq: u64,
+}
+
+impl Clone for HyperLogLog {
+    fn clone(&self) -> Self {
+        HyperLogLog {
+            b: self.b,
+            b_mask: self.b_mask,
+            m: self.m,
+            alpha: self.alpha,
+            registers: self.registers.clone(),
+            hasher_key0: self.hasher_key0,
+            hasher_key1: self.hasher_key1,
+            hash_algorithm: self.hash_algorithm,
+            hash_width: self.hash_width,
+            key_source: self.key_source,
+            register_cap: self.register_cap,
+            cached_estimate: AtomicU64::new(self.cached_estimate.load(Ordering::Relaxed)),
+            clamp_to_capacity: self.clamp_to_capacity,
+            caching: self.caching,
+            register_stamps: self.register_stamps.clone(),
+            insert_seq: self.insert_seq,
+        }
+    }
+}
+
+/// `HyperLogLog`のデバッグ用文字列を返す。
+impl fmt::Debug for HyperLogLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (est, est_method) = estimate_cardinality(self);
+        write!(f,
+        r#"HyperLogLog
+  estimated cardinality: {}
+  estimation method:     {:?}
+  -----------------------------------------------------
+  b:      {} bits (typical error rate: {}%)
+  m:      {} registers
+  alpha:  {}
+  hasher: {:?} ({}, {}), {} bits"#,
+               est,
+               est_method,
+               self.b,
+               self.typical_error_rate() * 100.0,
+               self.m,
+               self.alpha,
+               self.hash_algorithm,
+               self.hasher_key0,
+               self.hasher_key1,
+               self.hash_width.bits())
+    }
+}
+
+impl HyperLogLog {
+
+    /// `HyperLogLog`オブジェクトを作成する。bで指定したビット数をレジスタの
+    /// アドレッシングに使用する。bの範囲は4以上、16以下でなければならない
+    /// 範囲外なら`Err`を返す
+    pub fn new(b: u8) -> Result<Self, Box<dyn Error>> {
+        HyperLogLog::with_hasher(b, HashAlgorithm::SipHash24, HashWidth::Bits64)
+    }
+
+    /// `new`と同様だが、bをu8以外の整数型でも受け付ける。設定ファイルから読んだ値などを
+    /// そのまま渡すためのもの。bがu8に収まらない場合と、4以上、16以下でない場合は
+    /// それぞれ異なるメッセージの`Err`を返す
+    pub fn new_from<T: TryInto<u8>>(b: T) -> Result<Self, Box<dyn Error>> {
+        let b = b.try_into().map_err(|_| "b is too large for u8 (or negative); it must be between 4 and 16")?;
+        HyperLogLog::new(b)
+    }
+
+    /// ハッシュにSipHash-1-3を使う`HyperLogLog`オブジェクトを作成する。
+    /// `new`より要素の追加が速いが、`new`で作成したスケッチとはマージできない
+    pub fn with_siphash13(b: u8) -> Result<Self, Box<dyn Error>> {
+        HyperLogLog::with_hasher(b, HashAlgorithm::SipHash13, HashWidth::Bits64)
+    }
+
+    /// 32ビットのハッシュ値(SipHash-2-4の下位32ビット)を使う`HyperLogLog`オブジェクトを
+    /// 作成する。32ビットのハッシュ値を使う他の実装と見積もり値を合わせるためのもので、
+    /// ハッシュ値の衝突を補正する論文のラージレンジ補正を行う。
+    /// 64ビットのスケッチとはマージできない
+    pub fn with_32bit_hash(b: u8) -> Result<Self, Box<dyn Error>> {
+        HyperLogLog::with_hasher(b, HashAlgorithm::SipHash24, HashWidth::Bits32)
+    }
+
+    /// レジスタの値の上限をcapに制限した`HyperLogLog`オブジェクトを作成する。
+    /// レジスタをより少ないビット数で表現したときの、メモリと精度のトレードオフを
+    /// 調べるためのもの。capを超える値はcapに切り詰められる。
+    ///
+    /// 見積もりは切り詰めを補正しないため、カーディナリティがおよそm * 2^capに
+    /// 近づくと見積もり値は過小評価になり、それを超えると頭打ちになる。
+    /// capが1以上、65 - b以下でなければ`Err`を返す
+    pub fn with_register_cap(b: u8, cap: u8) -> Result<Self, Box<dyn Error>> {
+        let mut hll = HyperLogLog::new(b)?;
+        let ceiling = max_register_value(hll.hash_width, b);
+        if cap < 1 || cap > ceiling {
+            return Err(From::from(format!("cap must be between 1 and {}. cap = {}", ceiling, cap)))
+        }
+        hll.register_cap = cap;
+        Ok(hll)
+    }
+
+    fn with_hasher(b: u8, hash_algorithm: HashAlgorithm, hash_width: HashWidth) -> Result<Self, Box<dyn Error>> {
+        let (key0, key1) = os_random_keys()?;
+        let mut hll = HyperLogLog::with_parameters(b, key0, key1, hash_algorithm, hash_width)?;
+        hll.key_source = KeySource::Os;
+        Ok(hll)
+    }
+
+    /// ハッシュのキーをseedから決定的に導く`HyperLogLog`オブジェクトを作成する。
+    /// 同じseedで作成したスケッチは、プロセスをまたいでもマージできる。
+    /// キーを推測されると困る場合は`new`を使うこと。bが範囲外なら`Err`を返す
+    pub fn with_seed(b: u8, seed: u64) -> Result<Self, Box<dyn Error>> {
+        let (key0, key1) = keys_from_seed(seed);
+        let mut hll = HyperLogLog::with_parameters(b, key0, key1, HashAlgorithm::SipHash24, HashWidth::Bits64)?;
+        hll.key_source = KeySource::Seed(seed);
+        Ok(hll)
+    }
+
+    /// すべてのパラメータを指定して、空の`HyperLogLog`オブジェクトを作成する
+    fn with_parameters(b: u8, hasher_key0: u64, hasher_key1: u64, hash_algorithm: HashAlgorithm,
+                       hash_width: HashWidth) -> Result<Self, Box<dyn Error>> {
+        if !(4..=16).contains(&b) {
+            return Err(From::from(format!("b must be between 4 and 16. b = {}", b)))
+        }
+        HyperLogLog::with_registers(b, hasher_key0, hasher_key1, hash_algorithm, hash_width, vec![0; 1 << b])
+    }
+
+    /// selfとマージできる空の`HyperLogLog`オブジェクトを作成する。b、ハッシュのキー、
+    /// アルゴリズム、ビット数、レジスタの値の上限と、見積もり値の頭打ち、キャッシュの設定を引き継ぐ。
+    /// レジスタは共有しない。集約用のスケッチを既存のスケッチに合わせて用意するときに使う
+    pub fn empty_like(&self) -> HyperLogLog {
+        let mut empty = self.empty_with_b(self.b).expect("b is already validated");
+        empty.register_cap = self.register_cap;
+        empty.clamp_to_capacity = self.clamp_to_capacity;
+        empty.caching = self.caching;
+        empty
+    }
+
+    /// bだけが異なり、selfとハッシュのキー、アルゴリズム、ビット数が同じ空の
+    /// `HyperLogLog`オブジェクトを作成する
+    pub(crate) fn empty_with_b(&self, b: u8) -> Result<Self, Box<dyn Error>> {
+        let mut hll = HyperLogLog::with_parameters(b, self.hasher_key0, self.hasher_key1, self.hash_algorithm,
+                                                   self.hash_width)?;
+        hll.key_source = self.key_source;
+        Ok(hll)
+    }
+
+    /// b、ハッシュのキー、レジスタの配列から`HyperLogLog`オブジェクトを作成する。
+    /// registersは複製せずにそのまま所有する。ファイルなどから読み込んだレジスタを
+    /// 取り込むときに使う。registersの長さが2^bでない、値が上限(65 - b)を超える、
+    /// またはbが範囲外なら`Err`を返す
+    pub fn from_parts(b: u8, key0: u64, key1: u64, registers: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        let hll = HyperLogLog::with_registers(b, key0, key1, HashAlgorithm::SipHash24, HashWidth::Bits64,
+                                              registers)?;
+        hll.verify()?;
+        Ok(hll)
+    }
+
+    /// `registers_rle`で符号化したレジスタから`HyperLogLog`オブジェクトを作成する。
+    /// 連長の合計が2^bでない場合や、`from_parts`と同じ条件で`Err`を返す
+    pub fn from_rle(b: u8, key0: u64, key1: u64, runs: &[(u8, u32)]) -> Result<Self, Box<dyn Error>> {
+        get_alpha(b)?;
+        let m = 1usize << b;
+        let total = runs.iter().map(|&(_, len)| len as usize).sum::<usize>();
+        if total != m {
+            return Err(From::from(format!("run lengths must add up to {}. total = {}", m, total)))
+        }
+        let mut registers = Vec::with_capacity(m);
+        for &(x, len) in runs {
+            registers.extend(std::iter::repeat_n(x, len as usize));
+        }
+        HyperLogLog::from_parts(b, key0, key1, registers)
+    }
+
+    /// すべてのフィールドを指定して`HyperLogLog`オブジェクトを作成する。
+    /// registersの内容は検査しない
+    fn with_registers(b: u8, hasher_key0: u64, hasher_key1: u64, hash_algorithm: HashAlgorithm,
+                      hash_width: HashWidth, registers: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        // 構造体のフィールド`m`は2のb条。シフト演算で実装
+        let m     = 1 << b;
+        let alpha = get_alpha(b)?;
+
+        Ok(HyperLogLog {
+            alpha,
+            b,
+            b_mask: m - 1,
+            m,
+            registers,
+            hasher_key0,
+            hasher_key1,
+            hash_algorithm,
+            hash_width,
+            key_source: KeySource::Explicit,
+            register_cap: max_register_value(hash_width, b),
+            cached_estimate: AtomicU64::new(CACHE_INVALID),
+            clamp_to_capacity: false,
+            caching: true,
+            register_stamps: None,
+            insert_seq: 0,
+        })
+    }
+
+    /// 指定した典型的なエラー率を満たす最小のbで`HyperLogLog`オブジェクトを作成し、
+    /// 実際に得られたエラー率と共に返す。bは整数なので、得られるエラー率は
+    /// 指定値以下となる。b = 16でも満たせない場合は`Err`を返す
+    pub fn with_error_rate_reported(error: f64) -> Result<(Self, f64), Box<dyn Error>> {
+        if !error.is_finite() || error <= 0.0 {
+            return Err(From::from(format!("error must be a positive finite number. error = {}", error)))
+        }
+        let b = (4..17).find(|&b| typical_error_rate(1 << b) <= error)
+            .ok_or_else(|| format!("error rate {} cannot be achieved with b <= 16", error))?;
+        let hll = HyperLogLog::new(b)?;
+        let achieved = hll.typical_error_rate();
+        Ok((hll, achieved))
+    }
+
+    /// bで指定した精度の`HyperLogLog`オブジェクトを作成し、itemsのすべての要素を
+    /// 追加して返す。bが範囲外なら`Err`を返す
+    pub fn from_iter_with<I, H>(b: u8, items: I) -> Result<Self, Box<dyn Error>>
+        where I: IntoIterator<Item = H>, H: Hash
+    {
+        let mut hll = HyperLogLog::new(b)?;
+        for item in items {
+            hll.insert(&item);
+        }
+        Ok(hll)
+    }
+
+    /// `from_iter_with`と同様にスケッチを作成し、追加した要素の総数(重複を含む)と
+    /// 共に返す。総数を見積もり値で割れば、1要素あたりの平均の重複回数が分かる。
+    /// bが範囲外なら`Err`を返す
+    pub fn build_with_stats<I, H>(b: u8, items: I) -> Result<(Self, u64), Box<dyn Error>>
+        where I: IntoIterator<Item = H>, H: Hash
+    {
+        let mut hll = HyperLogLog::new(b)?;
+        let mut total = 0u64;
+        for item in items {
+            hll.insert(&item);
+            total += 1;
+        }
+        Ok((hll, total))
+    }
+
+    /// 計算済みの64ビットのハッシュ値を昇順に返すイテレータsortedから、
+    /// b、ハッシュのキー(key0, key1)の`HyperLogLog`オブジェクトを作成する。
+    /// 昇順なので同じハッシュ値は連続し、2つ目以降はレジスタを更新せずに読み飛ばす。
+    /// 昇順でなくても結果は正しいが、読み飛ばしが減る。`new`で作ったスケッチとマージする
+    /// 場合は、ハッシュ値をキー(key0, key1)のSipHash-2-4で求めなければならない。
+    /// bが範囲外なら`Err`を返す
+    pub fn from_sorted_hashes<I: Iterator<Item = u64>>(b: u8, key0: u64, key1: u64, sorted: I)
+        -> Result<Self, Box<dyn Error>>
+    {
+        let mut hll = HyperLogLog::with_parameters(b, key0, key1, HashAlgorithm::SipHash24, HashWidth::Bits64)?;
+        let mut previous = None;
+        for x in sorted {
+            if previous != Some(x) {
+                hll.update_register(x);
+                previous = Some(x);
+            }
+        }
+        Ok(hll)
+    }
+
+    /// 要素を追加する。要素は`std::hash::Hash`トレイトを実装していなければならない
+    pub fn insert<H: Hash>(&mut self, value: &H) {
+        let x = self.hash(value);
+        self.update_register(x);
+    }
+
+    /// aとbの組を1つの要素として追加する。`insert(&(a, b))`と同じハッシュ値になるが、
+    /// 組を作るために値を複製する必要がない
+    pub fn insert_pair<A: Hash, B: Hash>(&mut self, a: &A, b: &B) {
+        self.insert(&(a, b));
+    }
+
+    /// valueを`Display`で文字列に変換し、その文字列を要素として追加する。
+    /// `insert(&value.to_string())`と同じ。`Hash`を実装していない型(浮動小数点数など)や、
+    /// `Hash`の結果がプラットフォームに依存する型でも、表示が同じなら同じ要素として数える。
+    /// 文字列への変換を伴うため`insert`より遅い
+    pub fn insert_display<T: fmt::Display>(&mut self, value: &T) {
+        self.insert(&value.to_string());
+    }
+
+    /// valueが`Some`なら要素を追加し、`None`なら何もしない
+    pub fn insert_opt<H: Hash>(&mut self, value: Option<&H>) {
+        if let Some(v) = value {
+            self.insert(v);
+        }
+    }
+
+    /// 与えられたhasherで64ビットのハッシュ値を求めて要素を追加する。
+    /// ハッシュ関数の一貫性は呼び出し側の責任で、一つのスケッチに異なるハッシュ関数で
+    /// 要素を追加すると、同じ要素が重複して数えられ、見積もり値が壊れる
+    pub fn insert_with_hasher<H: Hash, S: Hasher>(&mut self, value: &H, mut hasher: S) {
+        value.hash(&mut hasher);
+        self.update_register(hasher.finish());
+    }
+
+    /// 要素を追加し、それによってレジスタの値が増えた場合はvalueの複製をnovelに追加する。
+    /// ストリームから重複の少ない要素を安価に抜き出すために使う。
+    ///
+    /// レジスタの値が増えるのは、同じハッシュ値の要素がまだ追加されていない場合だけなので、
+    /// 抜き出した要素は(ハッシュ値の衝突を除いて)必ず新しい要素である。ただし、新しい要素の
+    /// 多くはレジスタの値を増やさないため、新しい要素のごく一部しか抜き出せない。
+    /// その割合は、要素が増えるにつれて小さくなる
+    pub fn insert_and_collect_novel<H: Hash + Clone>(&mut self, value: &H, novel: &mut Vec<H>) {
+        let x = self.hash(value);
+        let (_, p1, previous) = self.update_register(x);
+        if previous < p1 {
+            novel.push(value.clone());
+        }
+    }
+
+    /// バイト列の要素をまとめて追加し、値が増えたレジスタの更新回数を返す。
+    /// 各要素は`insert(item)`と同じハッシュ値で追加される。
+    /// 更新回数が0に近づくほど、スケッチが飽和に近いことを示す
+    pub fn insert_batch_bytes(&mut self, items: &[&[u8]]) -> usize {
+        let mut changed = 0;
+        for item in items {
+            let x = self.hash(item);
+            let (_, p1, previous) = self.update_register(x);
+            if previous < p1 {
+                changed += 1;
+            }
+        }
+        changed
+    }
+
+    /// `insert`と同様に要素を追加し、更新対象のレジスタのインデックスjと、
+    /// ハッシュ値から求めた左端の1の位置p1(レジスタの値の上限で切り詰めた後の値)を返す。
+    /// 要素がどのレジスタに振り分けられたかを調べるためのもの
+    pub fn insert_debug<H: Hash>(&mut self, value: &H) -> (usize, u8) {
+        let x = self.hash(value);
+        let (j, p1, _) = self.update_register(x);
+        (j, p1)
+    }
+
+    /// 要素を追加した新しいスケッチを返す。ただし、追加してもレジスタが変化しない場合は
+    /// 複製せずに`None`を返す。不変なスケッチの版を積み重ねる用途で、状態が進んだときだけ
+    /// 複製のコストを払う
+    pub fn insert_cow<H: Hash>(&self, value: &H) -> Option<HyperLogLog> {
+        let x = self.hash(value);
+        let (j, p1) = self.register_position(x);
+        if self.registers[j] >= p1 {
+            return None
+        }
+        let mut next = self.clone();
+        next.update_register(x);
+        Some(next)
+    }
+
+    /// ハッシュ値xに対応するレジスタのインデックスと、左端の1の位置
+    /// (レジスタの値の上限で切り詰めた後の値)を返す
+    fn register_position(&self, x: u64) -> (usize, u8) {
+        let x = match self.hash_width {
+            HashWidth::Bits64 => x,
+            HashWidth::Bits32 => x & 0xffff_ffff,
+        };
+        let j = x as usize & self.b_mask;
+        let w = x >> self.b;
+        (j, position_of_leftmost_one_bit(w, self.hash_width.bits() - self.b).min(self.register_cap))
+    }
+
+    /// ハッシュ値xに対応するレジスタを更新し、そのインデックスと左端の1の位置、
+    /// 更新前のレジスタの値を返す
+    pub(crate) fn update_register(&mut self, x: u64) -> (usize, u8, u8) {
+        let (j, p1) = self.register_position(x);
+        let p2 = &mut self.registers[j];
+        let previous = *p2;
+        if *p2 < p1 {
+            *p2 = p1;
+            self.invalidate_cache();
+            if let Some(ref mut stamps) = self.register_stamps {
+                stamps[j] = self.insert_seq;
+            }
+        }
+        if self.register_stamps.is_some() {
+            self.insert_seq += 1;
+        }
+        (j, p1, previous)
+    }
+
+    /// カーディナリティの見積もり値を返す。見積もり値はレジスタが変化するまで
+    /// キャッシュされ、続けて呼び出した場合は再計算しない(`set_caching`で無効にできる)。
+    /// `set_clamp_to_capacity(true)`なら`capacity`を超えない値を返す
+    pub fn cardinality(&self) -> f64 {
+        let cached = self.cached_estimate.load(Ordering::Relaxed);
+        let est = if cached != CACHE_INVALID {
+            f64::from_bits(cached)
+        } else {
+            let est = estimate_cardinality(self).0;
+            if self.caching {
+                self.cached_estimate.store(est.to_bits(), Ordering::Relaxed);
+            }
+            est
+        };
+        if self.clamp_to_capacity {
+            est.min(self.capacity())
+        } else {
+            est
+        }
+    }
+
+    /// `cardinality`と同じ見積もり値を返すが、値が有限でなければpanicせずに`Err`を返す。
+    ///
+    /// 現在の推定ではこの`Err`は起こらない。`HyperLogLog`の式の分母(2^(-レジスタの値)の
+    /// 総和)は常に正で、`Linear Counting`は値が0のレジスタがある場合にだけ使い、
+    /// 見積もり値はハッシュ値の取りうる値の数で頭打ちにするため。空のスケッチは0、
+    /// 飽和したスケッチはその上限を返す。推定の変更で有限でない値が混入した場合の保険
+    pub fn try_cardinality(&self) -> Result<f64, Box<dyn Error>> {
+        let est = self.cardinality();
+        if est.is_finite() {
+            Ok(est)
+        } else {
+            Err(From::from(format!("the estimate is not finite. estimate = {}", est)))
+        }
+    }
+
+    /// スケッチが区別できるカーディナリティの上限m * 2^(レジスタの値の上限 - 1)を返す。
+    /// レジスタの値の上限が通常の値なら、ハッシュ値が取りうる値の数(2^64または2^32)に等しい
+    pub fn capacity(&self) -> f64 {
+        self.m as f64 * 2.0f64.powi(self.register_cap as i32 - 1)
+    }
+
+    /// 要素が追加されていない(すべてのレジスタが0の)スケッチなら`true`を返す
+    pub fn is_empty(&self) -> bool {
+        self.registers.iter().all(|&x| x == 0)
+    }
+
+    /// すべてのレジスタが上限に達している、または見積もり値がハッシュ値の取りうる値の数に
+    /// 達していて、見積もりが`Saturated`なら`true`を返す
+    pub fn is_saturated(&self) -> bool {
+        estimate_cardinality(self).1 == Estimator::Saturated
+    }
+
+    /// 新しい要素がrecent_insert_rate(単位時間あたりの異なる要素の数)で増え続けた場合に、
+    /// 見積もり値が`capacity`に達するまでの時間を返す。recent_insert_rateを1とすれば、
+    /// 残りの要素の数になる。すでに飽和している、またはrecent_insert_rateが正の
+    /// 有限値でなければ`None`を返す
+    pub fn projected_saturation(&self, recent_insert_rate: f64) -> Option<f64> {
+        if self.is_saturated() || !recent_insert_rate.is_finite() || recent_insert_rate <= 0.0 {
+            return None
+        }
+        let remaining = self.capacity() - estimate_cardinality(self).0;
+        if remaining <= 0.0 {
+            return None
+        }
+        Some(remaining / recent_insert_rate)
+    }
+
+    /// `cardinality`の見積もり値を`capacity`で頭打ちにするかを設定する。既定は`false`。
+    /// 表示用に極端な値を避けるためのもので、有効にすると飽和が見えなくなるため、
+    /// 監視では`is_saturated`を併せて確認すること
+    pub fn set_clamp_to_capacity(&mut self, clamp: bool) {
+        self.clamp_to_capacity = clamp;
+    }
+
+    /// `cardinality`の見積もり値をキャッシュするかを設定する。既定は`true`で、
+    /// レジスタが変化するまで見積もり値を再計算しない。`false`にすると、呼び出すたびに
+    /// レジスタ全体を走査して計算し直す。キャッシュ済みの値はここで破棄する
+    pub fn set_caching(&mut self, caching: bool) {
+        self.caching = caching;
+        self.invalidate_cache();
+    }
+
+    /// α値にalphaを使ってカーディナリティを見積もる。スケッチのα値は変更しない。
+    /// `f32-estimate`フィーチャーが有効ならalphaはf32に丸めて使う。
+    /// 論文ごとに異なるα値の影響を比べる実験用で、見積もり値はキャッシュしない
+    pub fn cardinality_with_alpha(&self, alpha: f64) -> f64 {
+        estimate_cardinality_with_alpha(self, alpha as Float).0
+    }
+
+    /// 各レジスタが最後に値を増やした挿入の通し番号を記録し始める。以降の`insert`などには
+    /// 0から順に通し番号が振られる。記録にはレジスタ1個につき8バイトを使う。
+    /// 有効にする前に値が増えたレジスタは、通し番号0で更新されたものとみなす
+    pub fn enable_recency_tracking(&mut self) {
+        if self.register_stamps.is_none() {
+            self.register_stamps = Some(vec![0; self.m]);
+        }
+    }
+
+    /// 次の挿入に振られる通し番号を返す。この値を`cardinality_recent`に渡すと、
+    /// 以降に追加した要素だけを見積もれる
+    pub fn insert_sequence(&self) -> u64 {
+        self.insert_seq
+    }
+
+    /// 通し番号がmin_seq以上の挿入で値が増えたレジスタだけを使って、カーディナリティを
+    /// 見積もる。それ以外のレジスタは0とみなす。
+    ///
+    /// これは近似で、最近の要素でもレジスタの値を増やさなかったものは数えないため、
+    /// 見積もり値は過小評価になる。逆に、古い要素が残したレジスタの値は除かれる。
+    /// `merge`や`set_register`による変更は通し番号を更新しない。
+    /// `enable_recency_tracking`を呼んでいなければ`Err`を返す
+    pub fn cardinality_recent(&self, min_seq: u64) -> Result<f64, Box<dyn Error>> {
+        let stamps = self.register_stamps.as_ref().ok_or("recency tracking is not enabled")?;
+        let recent = self.registers.iter().zip(stamps)
+            .map(|(&x, &seq)| if seq >= min_seq { x } else { 0 })
+            .collect::<Vec<_>>();
+        Ok(estimate_cardinality_of_registers(self.alpha, &recent).0)
+    }
+
+    /// `cardinality`の見積もり値を単精度で返す。f32の範囲を超える場合は`f32::MAX`を返す
+    pub fn cardinality_f32(&self) -> f32 {
+        self.cardinality().min(f32::MAX as f64) as f32
+    }
+
+    /// 一部のレジスタだけを使って、カーディナリティを大まかに素早く見積もる。
+    /// インデックスが2^kの倍数のレジスタ(全体のおよそsample_fraction)だけを使う。
+    /// これらはb - kビットのスケッチとして、全要素の1/2^kを数えているのと同じなので、
+    /// その見積もり値を2^k倍する。
+    ///
+    /// 典型的なエラー率は1.04 / √(m / 2^k)に増える。例えばb = 16でsample_fraction = 1/16
+    /// なら0.4%から1.6%になる。kはb - kが4以上になるように制限され、
+    /// sample_fractionが1以上なら`cardinality`と同じ値を返す
+    pub fn cardinality_approx(&self, sample_fraction: f64) -> f64 {
+        let k = ((1.0 / sample_fraction).log2().floor().max(0.0) as u8).min(self.b - 4);
+        if k == 0 {
+            return self.cardinality()
+        }
+        let sampled = self.registers.iter().step_by(1 << k).cloned().collect::<Vec<_>>();
+        // b - kは4以上なのでErrにはならない
+        let alpha = get_alpha(self.b - k).unwrap();
+        let est = estimate_cardinality_of_registers(alpha, &sampled).0 * (1u32 << k) as f64;
+        let space = self.hash_width.space();
+        if self.hash_width == HashWidth::Bits32 && est > space / 30.0 && est < space {
+            large_range_correction(est, space)
+        } else {
+            est
+        }
+    }
+
+    /// カーディナリティの見積もり値と、見積もりに使用したアルゴリズムを返す
+    pub fn cardinality_with_method(&self) -> (f64, Estimator) {
+        estimate_cardinality(self)
+    }
+
+    /// 現在のレジスタに適用できるすべての推定アルゴリズムの見積もり値を返す。
+    /// 値が大きく食い違う場合は、見積もりが難しい範囲にあることを示す。
+    /// 含まれるアルゴリズムの条件は`cardinality_with_method`の選択とは異なり、
+    /// `HyperLogLog`は常に、`LinerCounting`は値が0のレジスタがあるとき、
+    /// `LargeRange`は32ビットのハッシュ値を使うときに含まれる
+    pub fn all_estimates(&self) -> Vec<(Estimator, f64)> {
+        all_estimates(self)
+    }
+
+    /// 現在の見積もり値がどの程度信頼できるかを0以上1以下の値で返す。ダッシュボードでの
+    /// 目安として使う経験的な指標で、次の二つの積として求める。
+    ///
+    /// - `Linear Counting`と`HyperLogLog`の切り替え点(5m/2)の前後m/2以内では、
+    ///   切り替え点に近いほど0.5に向かって下がる。それ以外は1
+    /// - 値が上限に達していないレジスタの割合。上限に達したレジスタは、それ以上の要素を
+    ///   区別できない
+    ///
+    /// 見積もりが`Saturated`なら0を返す
+    pub fn confidence(&self) -> f64 {
+        let (est, method) = estimate_cardinality(self);
+        if method == Estimator::Saturated {
+            return 0.0
+        }
+        let m_64 = self.m as f64;
+        let distance = (est - 5.0 / 2.0 * m_64).abs() / (m_64 / 2.0);
+        let regime = 0.5 + 0.5 * distance.min(1.0);
+        let at_cap = self.registers.iter().filter(|&&x| x == self.register_cap).count();
+        regime * (1.0 - at_cap as f64 / m_64)
+    }
+
+    /// `Linear Counting`と`HyperLogLog`の切り替え点(5m/2)から、切り替えの判定に使う
+    /// 未補正の`HyperLogLog`の見積もり値までの符号付きの距離を、カーディナリティの単位で返す。
+    /// 負なら切り替え点より下(値が0のレジスタがあれば`Linear Counting`)、正なら上。
+    /// 0に近いほど見積もりの信頼性が低いので、その付近に留まるならbを上げる目安になる
+    pub fn regime_margin(&self) -> f64 {
+        let m = self.m as Float;
+        let sum = sum_and_count_zero_registers(&self.registers).0;
+        (raw_hyperloglog_estimate(self.alpha, m, sum) - 5.0 / 2.0 * m) as f64
+    }
+
+    /// 見積もり値のキャッシュを破棄し、次回の`cardinality`で再計算させる
+    pub fn invalidate_cache(&mut self) {
+        *self.cached_estimate.get_mut() = CACHE_INVALID;
+    }
+
+    /// 複数のスケッチのカーディナリティの見積もり値をまとめて返す
+    pub fn estimate_many(sketches: &[HyperLogLog]) -> Vec<f64> {
+        sketches.iter().map(HyperLogLog::cardinality).collect()
+    }
+
+    /// b から予想される典型的なエラー率を返す
+    pub fn typical_error_rate(&self) -> f64 {
+        typical_error_rate(self.m)
+    }
+
+    /// snapshotの時点から追加された新しい要素の数を、見積もり値の差として返す。
+    /// 見積もり誤差で負になる場合は0を返す。snapshotがselfの過去の状態(`clone`したもの)で
+    /// ある場合にだけ意味を持つ。別のスケッチを渡しても検出できない。
+    /// マージできなければ`Err`を返す
+    pub fn new_elements_since(&self, snapshot: &HyperLogLog) -> Result<f64, Box<dyn Error>> {
+        self.check_mergeable(snapshot)?;
+        Ok((self.cardinality() - snapshot.cardinality()).max(0.0))
+    }
+
+    /// otherとマージした場合の見積もり値について、予想される相対誤差(標準誤差)を返す。
+    /// 和集合の見積もりが`Linear Counting`によるなら、その標準誤差
+    /// √(m(e^t - t - 1)) / n (t = n / m)を、それ以外なら`typical_error_rate`を返す。
+    /// 和集合が`Saturated`なら誤差に上限がないので無限大を返す。
+    /// マージできなければ`Err`を返す
+    pub fn union_error_estimate(&self, other: &HyperLogLog) -> Result<f64, Box<dyn Error>> {
+        let mut union = self.clone();
+        union.merge(other)?;
+        let (est, method) = estimate_cardinality(&union);
+        Ok(match method {
+            Estimator::LinerCounting => linear_counting_error(self.m as f64, est),
+            Estimator::Saturated => f64::INFINITY,
+            Estimator::HyperLogLog | Estimator::LargeRange => self.typical_error_rate(),
+        })
+    }
+
+    /// 真のカーディナリティが分かっている場合に、見積もり値の相対誤差
+    /// `(見積もり値 - 真の値) / 真の値` を返す。運用中の誤差監視に使用する。
+    /// true_cardinalityが0の場合、結果は有限の値にならない
+    pub fn measured_error(&self, true_cardinality: u64) -> f64 {
+        let truth = true_cardinality as f64;
+        (self.cardinality() - truth) / truth
+    }
+
+    /// 二つのスケッチの見積もり値の相対差`(selfの見積もり値 - otherの見積もり値) / otherの見積もり値`
+    /// を返す。見積もり値を比べるだけなので、マージできないスケッチ同士でもよい。
+    /// 結果には両方の見積もり誤差が含まれるため、両者の典型的なエラー率を合わせた程度の
+    /// 差は有意ではない。otherの見積もり値が0(otherが空)なら相対差は定義できないので`None`を返す
+    pub fn relative_difference(&self, other: &HyperLogLog) -> Option<f64> {
+        let other_est = other.cardinality();
+        if other_est == 0.0 {
+            return None
+        }
+        Some((self.cardinality() - other_est) / other_est)
+    }
+
+    /// 内部状態の整合性を検査する。破損している可能性のあるストレージから
+    /// 読み込んだスケッチを信用する前に使う。不整合があれば`Err`を返す
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        if self.m != 1 << self.b {
+            return Err(From::from(format!("m must be 2^b. b = {}, m = {}", self.b, self.m)))
+        }
+        if self.registers.len() != self.m {
+            return Err(From::from(format!("registers.len() must be {}. registers.len() = {}",
+                                          self.m, self.registers.len())))
+        }
+        if self.b_mask != self.m - 1 {
+            return Err(From::from(format!("b_mask must be {}. b_mask = {}", self.m - 1, self.b_mask)))
+        }
+        if self.alpha != get_alpha(self.b)? {
+            return Err(From::from(format!("alpha does not match b. b = {}, alpha = {}", self.b, self.alpha)))
+        }
+        let ceiling = max_register_value(self.hash_width, self.b);
+        // 上限が0では要素を一つも記録できないので、`with_register_cap`と同じく拒否する
+        if self.register_cap < 1 || self.register_cap > ceiling {
+            return Err(From::from(format!("register cap must be between 1 and {}. register cap = {}",
+                                          ceiling, self.register_cap)))
+        }
+        let cap = self.register_cap;
+        if let Some((i, x)) = self.registers.iter().enumerate().find(|&(_, &x)| x > cap) {
+            return Err(From::from(format!("register value must be at most {}. registers[{}] = {}",
+                                          cap, i, x)))
+        }
+        Ok(())
+    }
+
+    /// index番目のレジスタの値を返す。範囲外なら`None`を返す
+    pub fn get_register(&self, index: usize) -> Option<u8> {
+        self.registers.get(index).cloned()
+    }
+
+    /// index番目のレジスタに値を設定する。indexが範囲外、またはvalueがレジスタの
+    /// 値の上限(通常は65 - b)を超えるなら`Err`を返す。見積もり器のテストなどで
+    /// 特定のレジスタの状態を作るために使う
+    pub fn set_register(&mut self, index: usize, value: u8) -> Result<(), Box<dyn Error>> {
+        if index >= self.m {
+            return Err(From::from(format!("index must be less than {}. index = {}", self.m, index)))
+        }
+        if value > self.register_cap {
+            return Err(From::from(format!("value must be at most {}. value = {}", self.register_cap, value)))
+        }
+        if self.registers[index] != value {
+            self.registers[index] = value;
+            self.invalidate_cache();
+        }
+        Ok(())
+    }
+
+    /// 値の大きい順にk個のレジスタだけを残し、残りを0にする。値が同じレジスタは
+    /// インデックスの小さいものを残す。スケッチの非可逆圧縮を研究するためのもので、
+    /// 見積もり値は大きく劣化する。本番用途には使わないこと
+    pub fn sparsify_to_top(&mut self, k: usize) {
+        let mut order = (0..self.m).collect::<Vec<_>>();
+        order.sort_by_key(|&j| std::cmp::Reverse(self.registers[j]));
+        let mut changed = false;
+        for &j in order.iter().skip(k) {
+            if self.registers[j] != 0 {
+                self.registers[j] = 0;
+                changed = true;
+            }
+        }
+        if changed {
+            self.invalidate_cache();
+        }
+    }
+
+    /// レジスタ配列への可変参照を返す。新しい見積もり器の実験など向けで、
+    /// `advanced`フィーチャーが必要。呼び出し側は、レジスタの値がその上限
+    /// (通常は65 - b)を超えないという不変条件を守らなければならない(`verify`で検査できる)。
+    /// 見積もり値のキャッシュはこの時点で破棄される。書き換え後に`recompute_cached`を呼ぶと
+    /// 見積もり値を計算し直してキャッシュする
+    #[cfg(feature = "advanced")]
+    pub fn registers_mut(&mut self) -> &mut [u8] {
+        self.invalidate_cache();
+        &mut self.registers
+    }
+
+    /// キャッシュしている値を現在のレジスタから計算し直す
+    #[cfg(feature = "advanced")]
+    pub fn recompute_cached(&mut self) {
+        self.invalidate_cache();
+        self.cardinality();
+    }
+
+    /// ハッシュの初期化に使うキーの組を返す
+    pub fn keys(&self) -> (u64, u64) {
+        (self.hasher_key0, self.hasher_key1)
+    }
+
+    /// ハッシュの初期化に使うキーの組を変更する。キーが変わるとそれまでに追加した要素と
+    /// ハッシュ値の対応が崩れるため、要素が追加されていない(すべてのレジスタが0の)
+    /// スケッチでなければ`Err`を返す
+    pub fn set_keys(&mut self, key0: u64, key1: u64) -> Result<(), Box<dyn Error>> {
+        if !self.is_empty() {
+            return Err(From::from("keys can only be changed on an empty sketch"))
+        }
+        self.hasher_key0 = key0;
+        self.hasher_key1 = key1;
+        self.key_source = KeySource::Explicit;
+        Ok(())
+    }
+
+    /// すべてのレジスタを0に戻し、ハッシュのキーをOSの乱数生成器で作り直す。bなどの設定は
+    /// そのまま。再利用したスケッチが、以前のデータのスケッチと誤ってマージされるのを防ぐ。
+    /// 挿入の通し番号の記録も最初からになる。乱数生成器を作成できなければ、
+    /// selfを変更せずに`Err`を返す
+    pub fn recycle(&mut self) -> Result<(), Box<dyn Error>> {
+        let (key0, key1) = os_random_keys()?;
+        self.hasher_key0 = key0;
+        self.hasher_key1 = key1;
+        self.key_source = KeySource::Os;
+        for x in &mut self.registers {
+            *x = 0;
+        }
+        if let Some(ref mut stamps) = self.register_stamps {
+            for seq in stamps.iter_mut() {
+                *seq = 0;
+            }
+        }
+        self.insert_seq = 0;
+        self.invalidate_cache();
+        Ok(())
+    }
+
+    /// ハッシュのキーの由来を返す。`new`などは`Os`、`with_seed`は`Seed`、キーを指定する
+    /// `from_parts`などは`Explicit`になる。シリアライズしても保持される
+    pub fn key_source(&self) -> KeySource {
+        self.key_source
+    }
+
+    /// レジスタの更新に使うハッシュアルゴリズムを返す
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    /// ハッシュ値のビット数を返す
+    pub fn hash_width(&self) -> HashWidth {
+        self.hash_width
+    }
+
+    /// 各スケッチが先頭のスケッチとマージできるかを検査し、その結果をまとめて返す。
+    /// 最初の不一致で失敗する`merge`と異なり、マージできないスケッチをすべて特定できるので、
+    /// それらを除いて残りをマージするといった使い方ができる
+    pub fn compatibility_report(sketches: &[&HyperLogLog]) -> CompatibilityReport {
+        let sketches = match sketches.first() {
+            Some(first) => sketches.iter().map(|s| SketchCompatibility {
+                b_matches: s.b == first.b,
+                keys_match: s.keys() == first.keys(),
+                hash_matches: s.hash_algorithm == first.hash_algorithm && s.hash_width == first.hash_width,
+            }).collect(),
+            None => Vec::new(),
+        };
+        CompatibilityReport { sketches }
+    }
+
+    /// otherとマージ可能(bとハッシュのキー、アルゴリズム、ビット数が一致する)なら`true`を返す。
+    /// キーの異なるスケッチをマージすると、エラーにならずに誤った見積もり値になるため、
+    /// 集計前の振り分けに使う
+    pub fn compatible_with(&self, other: &HyperLogLog) -> bool {
+        self.b == other.b && self.keys() == other.keys() && self.hash_algorithm == other.hash_algorithm
+            && self.hash_width == other.hash_width
+    }
+
+    /// 他の`HyperLogLog`オブジェクトをマージする。マージ後のスケッチは両者の和集合を
+    /// 見積もる。マージは可換かつ結合的で、順序によらず同じレジスタの状態になる。
+    /// ただし、otherの値がselfのレジスタの値の上限を超える場合は切り詰めるため、
+    /// 上限の異なるスケッチ同士では結果がマージの向きに依存する。
+    /// bとハッシュのキー、アルゴリズムが一致しなければ`Err`を返す
+    pub fn merge(&mut self, other: &HyperLogLog) -> Result<(), Box<dyn Error>> {
+        self.merge_with_margin(other, 0)
+    }
+
+    /// `merge`と同様にマージするが、レジスタの値がその最大値(65 - b)を超えていないかも検査する。
+    /// `merge`は上限を超えるotherの値を黙って切り詰めるため、破損したスケッチを見逃す。
+    /// 結果の各レジスタはselfとotherの最大値なので、マージの前に両方を検査すれば
+    /// マージ後の検査と同じになる。超えるレジスタがあれば、最初のものを示す`Err`を返し、
+    /// selfは変更しない
+    pub fn merge_strict(&mut self, other: &HyperLogLog) -> Result<(), Box<dyn Error>> {
+        self.check_mergeable(other)?;
+        let ceiling = max_register_value(self.hash_width, self.b);
+        for (name, sketch) in &[("self", &*self), ("other", other)] {
+            if let Some(i) = sketch.registers.iter().position(|&x| x > ceiling) {
+                return Err(From::from(format!("{}.registers[{}] = {} exceeds the maximum value {}",
+                                              name, i, sketch.registers[i], ceiling)))
+            }
+        }
+        self.merge(other)
+    }
+
+    /// bの異なるスケッチを、高い方の精度でマージする。精度の高い方を低い方のbに
+    /// 畳み込んでマージし、その結果を`upscale_view`と同じ規則で高い方のbに引き上げる。
+    /// 引き上げた値を精度の高い方のレジスタとそのまま比べると、同じ要素を重複して数えて
+    /// 過大評価になるため、いったん低い方の精度でそろえる。
+    ///
+    /// 結果の信頼性は精度の低い方のスケッチで決まる。精度をそろえて後のマージに
+    /// 備えるためのもので、精度を下げたままでよければ`merge_all_folded`を使うこと。
+    /// ハッシュのキー、アルゴリズム、ビット数が一致しなければ`Err`を返し、selfは変更しない
+    pub fn merge_upscaled(&mut self, other: &HyperLogLog) -> Result<(), Box<dyn Error>> {
+        if other.b == self.b {
+            return self.merge(other)
+        }
+        let (low, high) = if self.b < other.b { (&*self, other) } else { (other, &*self) };
+        let mut merged = low.clone();
+        merged.merge(&high.folded(low.b))?;
+        *self = merged.upscale_view(high.b)?;
+        Ok(())
+    }
+
+    /// `merge`と同様にマージするが、otherのレジスタの値がselfの値 + marginを超える
+    /// レジスタだけを更新する。margin = 0なら`merge`と同じ。報告ごとのばらつきが大きい
+    /// スケッチを集約する際に、小さな変動を無視するために使う。
+    /// marginが0でなければ和集合の一部を数え落とすので、見積もり値は過小評価になる
+    pub fn merge_with_margin(&mut self, other: &HyperLogLog, margin: u8) -> Result<(), Box<dyn Error>> {
+        self.check_mergeable(other)?;
+        // 空のスケッチが多い集約では、レジスタの比較を省く
+        if other.is_empty() {
+            return Ok(())
+        }
+        let cap = self.register_cap;
+        if margin == 0 && other.register_cap <= cap && self.is_empty() {
+            self.registers.copy_from_slice(&other.registers);
+            self.invalidate_cache();
+            return Ok(())
+        }
+        let mut changed = false;
+        for (x, &y) in self.registers.iter_mut().zip(&other.registers) {
+            let y = y.min(cap);
+            if x.saturating_add(margin) < y {
+                *x = y;
+                changed = true;
+            }
+        }
+        if changed {
+            self.invalidate_cache();
+        }
+        Ok(())
+    }
+
+    /// otherのレジスタのうち、changed_indicesに挙げたものだけをマージする。
+    /// 前回のマージ以降に変化したレジスタ(`register_diff`などで求める)だけを渡せば、
+    /// `merge`と同じ結果を変化量に比例する手間で得られる。
+    /// マージできない、またはm以上のインデックスがあれば、何も変更せずに`Err`を返す
+    pub fn merge_delta(&mut self, other: &HyperLogLog, changed_indices: &[usize]) -> Result<(), Box<dyn Error>> {
+        self.check_mergeable(other)?;
+        if let Some(&j) = changed_indices.iter().find(|&&j| j >= self.m) {
+            return Err(From::from(format!("index must be less than {}. index = {}", self.m, j)))
+        }
+        let cap = self.register_cap;
+        let mut changed = false;
+        for &j in changed_indices {
+            let y = other.registers[j].min(cap);
+            if self.registers[j] < y {
+                self.registers[j] = y;
+                changed = true;
+            }
+        }
+        if changed {
+            self.invalidate_cache();
+        }
+        Ok(())
+    }
+
+    /// スケッチの各組について、共通部分のカーディナリティを包除原理
+    /// |A ∩ B| = |A| + |B| - |A ∪ B|で見積もり、N×Nの行列で返す。対角成分は各スケッチの
+    /// 見積もり値。見積もり誤差で負になる値は0にする。誤差は和集合の大きさに比例するため、
+    /// 共通部分が小さいと相対誤差は大きくなる。マージできないスケッチがあれば`Err`を返す
+    pub fn pairwise_intersections(sketches: &[&HyperLogLog]) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+        let unions = HyperLogLog::pairwise_unions(sketches)?;
+        let n = sketches.len();
+        let mut matrix = unions.clone();
+        for i in 0..n {
+            for j in 0..n {
+                if i != j {
+                    matrix[i][j] = (unions[i][i] + unions[j][j] - unions[i][j]).max(0.0);
+                }
+            }
+        }
+        Ok(matrix)
+    }
+
+    /// スケッチの各組について、Jaccard係数|A ∩ B| / |A ∪ B|を見積もり、N×Nの対称行列で
+    /// 返す。共通部分は`pairwise_intersections`と同じく包除原理で見積もり、値は0以上1以下に
+    /// 丸める。対角成分は1。どちらも空のスケッチの組は同じ集合なので1とする。
+    /// マージできないスケッチがあれば`Err`を返す
+    pub fn jaccard_matrix(sketches: &[&HyperLogLog]) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+        let unions = HyperLogLog::pairwise_unions(sketches)?;
+        let n = sketches.len();
+        let mut matrix = vec![vec![1.0; n]; n];
+        for i in 0..n {
+            for j in 0..n {
+                if i != j && unions[i][j] > 0.0 {
+                    let intersection = (unions[i][i] + unions[j][j] - unions[i][j]).max(0.0);
+                    matrix[i][j] = (intersection / unions[i][j]).min(1.0);
+                }
+            }
+        }
+        Ok(matrix)
+    }
+
+    /// 各レジスタの値をmax(0, selfの値 - otherの値)としたスケッチを返す。
+    /// 「selfにしか含まれないと思われる要素」をレジスタ単位で表す経験的な近似で、
+    /// 要素単位の差集合ではなく、見積もり値に理論的な誤差の保証はない。
+    /// 探索的な分析のためだけに使うこと。マージできなければ`Err`を返す
+    pub fn max_minus(&self, other: &HyperLogLog) -> Result<HyperLogLog, Box<dyn Error>> {
+        self.check_mergeable(other)?;
+        let mut result = self.empty_like();
+        for ((r, &x), &y) in result.registers.iter_mut().zip(&self.registers).zip(&other.registers) {
+            *r = x.saturating_sub(y);
+        }
+        Ok(result)
+    }
+
+    /// 各組の和集合の見積もり値をN×Nの対称行列で返す。対角成分は各スケッチの見積もり値。
+    /// マージできないスケッチがあれば`Err`を返す
+    fn pairwise_unions(sketches: &[&HyperLogLog]) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+        if let Some(first) = sketches.first() {
+            for sketch in &sketches[1..] {
+                first.check_mergeable(sketch)?;
+            }
+        }
+        let n = sketches.len();
+        let mut unions = vec![vec![0.0; n]; n];
+        for i in 0..n {
+            unions[i][i] = sketches[i].cardinality();
+            for j in (i + 1)..n {
+                let mut union = sketches[i].clone();
+                union.merge(sketches[j])?;
+                unions[i][j] = union.cardinality();
+                unions[j][i] = unions[i][j];
+            }
+        }
+        Ok(unions)
+    }
+
+    /// イテレータが返すスケッチを一つずつマージし、その和集合を返す。すべてのスケッチを
+    /// 同時にメモリに載せずに集計できる。イテレータが空なら`None`を返し、
+    /// マージできないスケッチがあれば`Err`を返す
+    pub fn reduce<I: Iterator<Item = HyperLogLog>>(mut iter: I) -> Result<Option<HyperLogLog>, Box<dyn Error>> {
+        let mut acc = match iter.next() {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        for hll in iter {
+            acc.merge(&hll)?;
+        }
+        Ok(Some(acc))
+    }
+
+    /// レジスタを先頭から2^k個の連続した区間に分け、それぞれを精度b - kの独立した
+    /// スケッチとして返す。各スケッチは全要素のおよそ1/2^kを数えたものになる。
+    /// アンサンブル推定器の研究などに使う。b - kが4未満なら`Err`を返す
+    pub fn split(&self, k: u8) -> Result<Vec<HyperLogLog>, Box<dyn Error>> {
+        if k > self.b - 4 {
+            return Err(From::from(format!("k must be at most b - 4. b = {}, k = {}", self.b, k)))
+        }
+        let part_b = self.b - k;
+        self.registers.chunks(1 << part_b).map(|chunk| {
+            let mut part = self.empty_with_b(part_b)?;
+            part.register_cap = self.register_cap;
+            part.registers.copy_from_slice(chunk);
+            Ok(part)
+        }).collect()
+    }
+
+    /// 精度の低いスケッチを、精度new_bのスケッチとして表示するためのビューを返す。
+    /// 各レジスタの値からnew_b - bを引いた値を、new_bでそのレジスタに対応する
+    /// 2^(new_b - b)個のレジスタに書き込む(0のレジスタは0のまま)。
+    ///
+    /// 情報は増えないため精度は向上しないが、ビューの`cardinality`は元のスケッチの
+    /// 見積もり値とおよそ等しくなる。
+    /// new_bがbより小さい、または範囲外なら`Err`を返す
+    pub fn upscale_view(&self, new_b: u8) -> Result<HyperLogLog, Box<dyn Error>> {
+        if new_b < self.b {
+            return Err(From::from(format!("new_b must be at least b. b = {}, new_b = {}", self.b, new_b)))
+        }
+        let mut view = self.empty_with_b(new_b)?;
+        // 要素ごとの値(x >> bの左端の1の位置)はbによらず変わらないが、new_bでは親の
+        // レジスタの要素がおよそ1 / 2^(new_b - b)ずつ各レジスタに分かれる。その分だけ
+        // 最大値の期待値が小さくなるので、統計的な補正として各レジスタの値から差し引く
+        let shift = new_b - self.b;
+        view.register_cap = self.register_cap.min(view.register_cap);
+        for (j, x) in view.registers.iter_mut().enumerate() {
+            *x = self.registers[j & self.b_mask].saturating_sub(shift).min(view.register_cap);
+        }
+        Ok(view)
+    }
+
+    /// otherとの間で値が異なるレジスタを`(インデックス, selfの値, otherの値)`の形で返す。
+    /// 等しいはずのスケッチの見積もり値が異なる原因を調べるのに使う。
+    /// マージできない組み合わせなら`Err`を返す
+    #[allow(clippy::type_complexity)]
+    pub fn register_diff(&self, other: &HyperLogLog) -> Result<Vec<(usize, u8, u8)>, Box<dyn Error>> {
+        self.check_mergeable(other)?;
+        Ok(self.registers.iter().zip(&other.registers).enumerate()
+           .filter(|&(_, (x, y))| x != y)
+           .map(|(i, (&x, &y))| (i, x, y))
+           .collect())
+    }
+
+    /// 精度をtarget_b(b以下)に下げたスケッチを返す。各要素をtarget_bで追加した場合と
+    /// 同じレジスタの状態になる
+    pub(crate) fn folded(&self, target_b: u8) -> HyperLogLog {
+        let mut folded = self.empty_with_b(target_b).expect("target_b must be between 4 and b");
+        self.fold_into(&mut folded);
+        folded
+    }
+
+    /// 精度を下げたスケッチtarget(bはself以下)に、selfのレジスタを畳み込んでマージする
+    fn fold_into(&self, target: &mut HyperLogLog) {
+        let ceiling = max_register_value(self.hash_width, self.b);
+        for (j, &x) in self.registers.iter().enumerate() {
+            // 残りのビットがすべて0だった要素は、target.bではアドレッシングに使わなくなった
+            // インデックスの上位ビットまで含めて左端の1の位置を数え直す
+            let x = if x == ceiling {
+                ceiling - 1 + position_of_leftmost_one_bit((j >> target.b) as u64, self.b - target.b)
+            } else {
+                x
+            };
+            let y = &mut target.registers[j & target.b_mask];
+            if *y < x {
+                *y = x;
+            }
+        }
+    }
+
+    /// 複数のスケッチの精度をtarget_bに下げながら、一つのスケッチにマージする。
+    /// 各スケッチを個別に`target_b`へ畳み込んでからマージするのと同じ結果になるが、
+    /// 中間のスケッチを作らない。スケッチが空である、ハッシュのキー、アルゴリズム、
+    /// ビット数が一致しない、またはbがtarget_b未満のスケッチがあれば`Err`を返す
+    pub fn merge_all_folded(sketches: &[HyperLogLog], target_b: u8) -> Result<HyperLogLog, Box<dyn Error>> {
+        let first = sketches.first().ok_or("at least one sketch is required")?;
+        let mut merged = first.empty_with_b(target_b)?;
+        for (i, sketch) in sketches.iter().enumerate() {
+            if sketch.keys() != first.keys() || sketch.hash_algorithm != first.hash_algorithm
+                || sketch.hash_width != first.hash_width {
+                return Err(From::from(format!(
+                    "sketches must share hasher keys, hash algorithm and hash width. index = {}", i)))
+            }
+            if sketch.b < target_b {
+                return Err(From::from(format!("b must be at least {}. sketches[{}].b = {}",
+                                              target_b, i, sketch.b)))
+            }
+            sketch.fold_into(&mut merged);
+        }
+        Ok(merged)
+    }
+
+    /// 複数のスケッチを、隣り合う2つずつマージする木構造の畳み込みで一つにマージする。
+    /// 結果は順にマージするのと同じ。マージを始める前にすべてのスケッチがマージ可能か
+    /// 検査し、スケッチが空である、またはマージできないスケッチがあれば`Err`を返す
+    pub fn merge_tree(sketches: Vec<HyperLogLog>) -> Result<HyperLogLog, Box<dyn Error>> {
+        {
+            let first = sketches.first().ok_or("at least one sketch is required")?;
+            for (i, sketch) in sketches.iter().enumerate().skip(1) {
+                first.check_mergeable(sketch)
+                    .map_err(|e| format!("sketches[{}] cannot be merged: {}", i, e))?;
+            }
+        }
+        let mut level = sketches;
+        while level.len() > 1 {
+            let mut next = Vec::with_capacity(level.len().div_ceil(2));
+            let mut iter = level.into_iter();
+            while let Some(mut left) = iter.next() {
+                if let Some(right) = iter.next() {
+                    left.merge(&right).expect("compatibility is checked up front");
+                }
+                next.push(left);
+            }
+            level = next;
+        }
+        Ok(level.pop().expect("at least one sketch remains"))
+    }
+
+    /// マージ可能か(bとハッシュのキー、アルゴリズムが一致するか)を検査する。
+    /// アルゴリズムが異なる場合はキーも異なることが多いため、原因が分かるように
+    /// アルゴリズムを先に検査する
+    pub(crate) fn check_mergeable(&self, other: &HyperLogLog) -> Result<(), Box<dyn Error>> {
+        if self.hash_algorithm != other.hash_algorithm {
+            return Err(From::from(format!(
+                "hash algorithms must be the same. self: {:?}, other: {:?}. \
+                 Sketches built with different hash algorithms (e.g. `new` and `with_siphash13`) \
+                 cannot be merged", self.hash_algorithm, other.hash_algorithm)))
+        }
+        if self.hash_width != other.hash_width {
+            return Err(From::from(format!("hash widths must be the same. self: {} bits, other: {} bits",
+                                          self.hash_width.bits(), other.hash_width.bits())))
+        }
+        if self.b != other.b {
+            return Err(From::from(format!("b must be the same. self.b = {}, other.b = {}", self.b, other.b)))
+        }
+        if self.keys() != other.keys() {
+            return Err(From::from("hasher keys must be the same"))
+        }
+        Ok(())
+    }
+
+    /// スケッチの状態(b、ハッシュのキーとアルゴリズム、レジスタ)から64ビットの
+    /// 指紋を求める。固定キーのハッシュを使うので、プロセスをまたいでも同じ状態なら
+    /// 同じ値になる。計算結果のキャッシュのキーなどに使う
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = fixed_key_hasher();
+        hasher.write_u8(self.b);
+        hasher.write(&self.hasher_key0.to_le_bytes());
+        hasher.write(&self.hasher_key1.to_le_bytes());
+        hasher.write_u8(self.hash_algorithm.tag());
+        hasher.write_u8(self.hash_width.bits());
+        hasher.write(&self.registers);
+        hasher.finish()
+    }
+
+    /// スケッチをバイト列にシリアライズする。ヘッダーに続けてすべてのレジスタを書き込む
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header(FORMAT_DENSE);
+        bytes.extend_from_slice(&self.registers);
+        append_checksum(bytes)
+    }
+
+    /// スケッチをバイト列にシリアライズする。値が0でないレジスタが少ない場合は、
+    /// その個数(u32)と`(インデックス(u16), 値)`の組だけを書き込むので、`to_bytes`より
+    /// 大幅に小さくなる。そうでなければ`to_bytes`と同じ形式になる
+    pub fn to_bytes_compressed(&self) -> Vec<u8> {
+        let non_zero = self.registers.iter().enumerate().filter(|&(_, &x)| x != 0).collect::<Vec<_>>();
+        if 4 + 3 * non_zero.len() >= self.m {
+            return self.to_bytes()
+        }
+        let mut bytes = self.header(FORMAT_SPARSE);
+        bytes.extend_from_slice(&(non_zero.len() as u32).to_le_bytes());
+        for (j, &x) in non_zero {
+            bytes.extend_from_slice(&(j as u16).to_le_bytes());
+            bytes.push(x);
+        }
+        append_checksum(bytes)
+    }
+
+    /// シリアライズ形式のヘッダーを作る
+    fn header(&self, version: u8) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.m + CHECKSUM_LEN);
+        bytes.push(version);
+        bytes.push(self.b);
+        bytes.push(self.hash_algorithm.tag());
+        bytes.push(self.hash_width.bits());
+        bytes.push(self.register_cap);
+        bytes.extend_from_slice(&self.hasher_key0.to_le_bytes());
+        bytes.extend_from_slice(&self.hasher_key1.to_le_bytes());
+        bytes.push(self.key_source.tag());
+        let seed = match self.key_source {
+            KeySource::Seed(seed) => seed,
+            _ => 0,
+        };
+        bytes.extend_from_slice(&seed.to_le_bytes());
+        bytes
+    }
+
+    /// レジスタの配列を連長圧縮し、`(値, 連長)`の組の列で返す。値が0のレジスタが
+    /// 長く続くスケッチでは、レジスタの配列より大幅に小さくなる。
+    /// b、ハッシュのキーと合わせて`from_rle`で復元できる
+    pub fn registers_rle(&self) -> Vec<(u8, u32)> {
+        let mut runs: Vec<(u8, u32)> = Vec::new();
+        for &x in &self.registers {
+            match runs.last_mut() {
+                Some(&mut (value, ref mut len)) if value == x => *len += 1,
+                _ => runs.push((x, 1)),
+            }
+        }
+        runs
+    }
+
+    /// スケッチを`to_bytes_compressed`の形式でシリアライズし、Base64の文字列で返す。
+    /// ログやJSON、YAMLの文字列にそのまま埋め込める
+    pub fn registers_base64(&self) -> String {
+        base64::encode(&self.to_bytes_compressed())
+    }
+
+    /// `registers_base64`の文字列からスケッチを復元する。Base64として正しくない、
+    /// またはバイト列が壊れていれば`Err`を返す
+    pub fn from_base64(s: &str) -> Result<Self, Box<dyn Error>> {
+        let bytes = base64::decode(s.trim()).ok_or("invalid base64 string")?;
+        HyperLogLog::from_bytes(&bytes)
+    }
+
+    /// `to_bytes`と同じ形式で、スケッチをwに書き込む。中間のバッファを確保しない
+    pub fn to_writer<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let header = self.header(FORMAT_DENSE);
+        let mut crc = Crc32::new();
+        crc.update(&header);
+        crc.update(&self.registers);
+        w.write_all(&header)?;
+        w.write_all(&self.registers)?;
+        w.write_all(&crc.finish().to_le_bytes())
+    }
+
+    /// `to_bytes`または`to_bytes_compressed`でシリアライズしたバイト列からスケッチを
+    /// 復元する。形式は先頭のバージョンから判別する。バイト列が壊れていれば`Err`を返す。
+    /// 特に、チェックサムが一致しなければ"checksum mismatch"を含む`Err`を返す
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut rest = bytes;
+        let hll = HyperLogLog::from_reader(&mut rest)?;
+        if !rest.is_empty() {
+            return Err(From::from(format!("{} trailing bytes after the sketch", rest.len())))
+        }
+        Ok(hll)
+    }
+
+    /// `from_bytes`と同様に、rから一つのスケッチを読み込んで復元する。
+    /// スケッチの末尾より先は読まない
+    pub fn from_reader<R: Read>(r: &mut R) -> Result<Self, Box<dyn Error>> {
+        // 読んだバイト列のチェックサムを求めながら読む
+        let mut r = ChecksumReader { inner: r, crc: Crc32::new() };
+        let mut header = [0; HEADER_V1_LEN];
+        r.read_exact(&mut header)?;
+        let (version, b, cap) = (header[0], header[1], header[4]);
+        get_alpha(b)?;
+        let hash_algorithm = HashAlgorithm::from_tag(header[2])
+            .ok_or_else(|| format!("unknown hash algorithm. tag = {}", header[2]))?;
+        let hash_width = match header[3] {
+            64 => HashWidth::Bits64,
+            32 => HashWidth::Bits32,
+            bits => return Err(From::from(format!("unknown hash width. bits = {}", bits))),
+        };
+        let key0 = read_u64_le(&header[5..13]);
+        let key1 = read_u64_le(&header[13..HEADER_V1_LEN]);
+        let key_source = match version {
+            FORMAT_DENSE | FORMAT_SPARSE | FORMAT_DENSE_V2 | FORMAT_SPARSE_V2 => {
+                let mut source = [0; HEADER_LEN - HEADER_V1_LEN];
+                r.read_exact(&mut source)?;
+                let seed = read_u64_le(&source[1..]);
+                let key_source = KeySource::from_tag(source[0], seed)
+                    .ok_or_else(|| format!("unknown key source. tag = {}", source[0]))?;
+                if key_source == KeySource::Seed(seed) && keys_from_seed(seed) != (key0, key1) {
+                    return Err(From::from("hasher keys do not match the recorded seed"))
+                }
+                key_source
+            }
+            _ => KeySource::Explicit,
+        };
+
+        let m = 1 << b;
+        let mut registers = vec![0; m];
+        match version {
+            FORMAT_DENSE | FORMAT_DENSE_V2 | FORMAT_DENSE_V1 => r.read_exact(&mut registers)?,
+            FORMAT_SPARSE | FORMAT_SPARSE_V2 | FORMAT_SPARSE_V1 => {
+                let mut count = [0; 4];
+                r.read_exact(&mut count)?;
+                for _ in 0..u32::from_le_bytes(count) {
+                    let mut entry = [0; 3];
+                    r.read_exact(&mut entry)?;
+                    let j = u16::from_le_bytes([entry[0], entry[1]]) as usize;
+                    if j >= m {
+                        return Err(From::from(format!("register index must be less than {}. index = {}", m, j)))
+                    }
+                    registers[j] = entry[2];
+                }
+            }
+            _ => return Err(From::from(format!("unknown format version. version = {}", version))),
+        }
+        if version == FORMAT_DENSE || version == FORMAT_SPARSE {
+            let expected = r.crc.finish();
+            let mut checksum = [0; CHECKSUM_LEN];
+            r.inner.read_exact(&mut checksum)?;
+            let actual = u32::from_le_bytes(checksum);
+            if actual != expected {
+                return Err(From::from(format!("checksum mismatch. expected = {:08x}, actual = {:08x}",
+                                              expected, actual)))
+            }
+        }
+
+        let mut hll = HyperLogLog::with_registers(b, key0, key1, hash_algorithm, hash_width, registers)?;
+        hll.register_cap = cap;
+        hll.key_source = key_source;
+        hll.verify()?;
+        Ok(hll)
+    }
+
+    /// 与えられたvalueに対する64ビットのハッシュ値を求める。
+    pub(crate) fn hash<H: Hash>(&self, value: &H) -> u64 {
+        hash_value(self.hash_algorithm, self.hasher_key0, self.hasher_key1, value)
+    }
+
+    /// b、m、α値、見積もり値、見積もりに使ったアルゴリズム、値が0のレジスタの数を
+    /// JSONオブジェクトの文字列で返す。構造化ログ向けの`Debug`出力に相当する
+    pub fn to_json(&self) -> String {
+        let (est, method) = estimate_cardinality(self);
+        let zeros = sum_and_count_zero_registers(&self.registers).1;
+        format!(r#"{{"b":{},"m":{},"alpha":{},"estimate":{},"method":"{:?}","zero_registers":{}}}"#,
+                self.b, self.m, self.alpha, est, method, zeros)
+    }
+
+    /// 見積もり値、レジスタの数、値が0のレジスタの数、飽和しているか(`is_saturated`なら1、
+    /// そうでなければ0)をPrometheusのテキスト形式で返す。
+    /// 各メトリクス名はnameを接頭辞とする。nameはPrometheusのメトリクス名として
+    /// 正しい文字列でなければならない
+    pub fn to_prometheus(&self, name: &str) -> String {
+        let zeros = sum_and_count_zero_registers(&self.registers).1;
+        let metrics = [
+            ("estimated_cardinality", "Estimated number of distinct elements.", self.cardinality()),
+            ("registers", "Number of registers (2^b).", self.m as f64),
+            ("zero_registers", "Number of registers that have never been updated.", zeros as f64),
+            ("saturated", "Whether the sketch is saturated (1) or not (0).", if self.is_saturated() { 1.0 } else { 0.0 }),
+        ];
+        metrics.iter().map(|&(metric, help, value)| {
+            format!("# HELP {0}_{1} {2}\n# TYPE {0}_{1} gauge\n{0}_{1} {3}\n", name, metric, help, value)
+        }).collect()
+    }
+
+    /// レジスタの値ごとに、その値を持つレジスタの個数を返す
+    fn register_value_counts(&self) -> BTreeMap<u8, usize> {
+        let mut map = BTreeMap::new();
+        for x in &self.registers {
+            let count = map.entry(*x).or_insert(0);
+            *count += 1;
+        }
+        map
+    }
+
+    /// 値がv以下のレジスタの割合がmass以上になる最小のvを返す。massが1を超える場合は
+    /// レジスタの最大値を返す。正常なスケッチではレジスタの値はほぼ幾何分布に従うので、
+    /// 期待から大きく外れていればハッシュ関数の問題を疑う手がかりになる
+    pub fn cumulative_register_value(&self, mass: f64) -> u8 {
+        let m_64 = self.m as f64;
+        let mut cumulative = 0;
+        let counts = self.register_value_counts();
+        for (&v, &count) in &counts {
+            cumulative += count;
+            if cumulative as f64 / m_64 >= mass {
+                return v
+            }
+        }
+        *counts.keys().last().expect("there is at least one register")
+    }
+
+    /// 最も多くのレジスタが持つ値(最頻値)を返す。同数の値が複数あれば最も小さい値を返す。
+    /// カーディナリティがmより十分大きい正常なスケッチでは、最頻値はおよそ
+    /// log2(カーディナリティ / m)になる。これから大きく外れていれば、飽和やハッシュの偏りを疑う
+    pub fn register_mode(&self) -> u8 {
+        let mut mode = (0, 0);
+        for (v, count) in self.register_value_counts() {
+            if count > mode.1 {
+                mode = (v, count);
+            }
+        }
+        mode.0
+    }
+
+    /// レジスタの値ごとに、その値を持つレジスタの割合(個数 / m)を返す。
+    /// bの異なるスケッチのレジスタの分布を比べるために使う
+    pub fn register_value_fractions(&self) -> BTreeMap<u8, f64> {
+        let m_64 = self.m as f64;
+        self.register_value_counts().into_iter()
+            .map(|(v, count)| (v, count as f64 / m_64))
+            .collect()
+    }
+
+    /// レジスタに格納された値について、その分布を示すヒストグラムを返す。
+    pub fn histgram_of_register_value_distribution(&self) -> String {
+        let mut histgram = Vec::new();
+
+        let map = self.register_value_counts();
+
+        if let (Some(last_reg_value), Some(max_count)) = (map.keys().last(), map.values().max()) {
+            // グラフの最大幅 = 40文字
+            let width = 40.0;
+            let rate  = width / (*max_count as f64);
+
+            for i in 0..(last_reg_value + 1) {
+                let mut line = format!("{:3}: ", i);
+
+                if let Some(count) = map.get(&i) {
+                    // アスタリスク(*)で横棒を描く
+                    let h_bar = "*".repeat((*count as f64 * rate).ceil() as usize);
+                    line.push_str(&h_bar);
+                    line.push_str(&format!("  {}", count));                    
+                } else {
+                    line.push('0');
+                };
+
+                histgram.push(line);
+            }
+        }
+        histgram.join("\n")
+    }
+
+    /// レジスタの値の分布を示す幅width、高さheightの棒グラフを、単独で表示できる
+    /// SVG文字列として返す。各棒の下にレジスタの値、上にレジスタの個数を表示する
+    pub fn register_histogram_svg(&self, width: u32, height: u32) -> String {
+        let map = self.register_value_counts();
+        let last_reg_value = *map.keys().last().expect("there is at least one register") as usize;
+        let max_count = *map.values().max().expect("there is at least one register") as f64;
+
+        // 上下にラベルを描く余白を取る
+        let label = 14.0;
+        let plot_height = (height as f64 - 2.0 * label).max(0.0);
+        let bar_width = width as f64 / (last_reg_value + 1) as f64;
+
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" \
+                               viewBox=\"0 0 {0} {1}\" font-family=\"sans-serif\" font-size=\"10\">\n",
+                              width, height);
+        for i in 0..(last_reg_value + 1) {
+            let count = map.get(&(i as u8)).cloned().unwrap_or(0);
+            let bar_height = plot_height * count as f64 / max_count;
+            let x = bar_width * i as f64;
+            let y = label + plot_height - bar_height;
+            let center = x + bar_width / 2.0;
+            svg.push_str(&format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"steelblue\"/>\n",
+                                  x + bar_width * 0.1, y, bar_width * 0.8, bar_height));
+            svg.push_str(&format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>\n",
+                                  center, y - 2.0, count));
+            svg.push_str(&format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>\n",
+                                  center, height as f64 - 2.0, i));
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+    
+}
+
+/// bytesの末尾にそのCRC-32(リトルエンディアン)を付けて返す
+fn append_checksum(mut bytes: Vec<u8>) -> Vec<u8> {
+    let checksum = crc32::checksum(&bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+/// 読んだバイト列のCRC-32を求めながら読み込む`Read`
+struct ChecksumReader<'a, R: 'a> {
+    inner: &'a mut R,
+    crc: Crc32,
+}
+
+impl<'a, R: Read> Read for ChecksumReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// ハッシュのキーの組をOSの乱数生成器で作る
+pub(crate) fn os_random_keys() -> Result<(u64, u64), Box<dyn Error>> {
+    let mut rng = rand::OsRng::new().map_err(|e| format!("Failed to create an OS RNG: {}", e))?;
+    Ok((rng.gen(), rng.gen()))
+}
+
+/// 8バイトのバイト列をリトルエンディアンのu64として読む
+fn read_u64_le(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+// テストケース
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[allow(deprecated)]
+    use std::hash::SipHasher;
+
+    #[test]
+    fn new_from() {
+        assert_eq!(HyperLogLog::new_from(12u32).unwrap().b, 12);
+        assert_eq!(HyperLogLog::new_from(4usize).unwrap().b, 4);
+        let too_large = HyperLogLog::new_from(300u32).unwrap_err().to_string();
+        assert!(too_large.contains("too large for u8"), "{}", too_large);
+        assert!(HyperLogLog::new_from(-1i32).is_err());
+        let out_of_range = HyperLogLog::new_from(17u32).unwrap_err().to_string();
+        assert!(out_of_range.contains("between 4 and 16") && !out_of_range.contains("u8"), "{}", out_of_range);
+    }
+
+    #[test]
+    fn create_hll() {
+
+        assert!(HyperLogLog::new(3).is_err());
+        assert!(HyperLogLog::new(17).is_err());
+
+        let hll = HyperLogLog::new(4);
+        assert!(hll.is_ok());
+
+        let hll = hll.unwrap();
+        assert_eq!(hll.b, 4);
+        assert_eq!(hll.m, 2_f64.powi(4) as usize);
+        assert_eq!(hll.alpha, 0.673);
+        assert_eq!(hll.registers.len(), 2_f64.powi(4) as usize);
+
+        assert!(HyperLogLog::new(16).is_ok());
+    }
+
+    #[test]
+    fn small_range() {
+        let mut hll = HyperLogLog::new(12).unwrap();
+        let items = ["test1", "test2", "test3", "test2", "test2", "test2"];
+
+        println!("\n=== Loading {} items.\n", items.len());
+        for item in &items {
+            hll.insert(item);
+        }
+
+    }
+
+    #[test]
+    fn measured_error() {
+        let mut hll = HyperLogLog::new(12).unwrap();
+        for i in 0..1000 {
+            hll.insert(&i);
+        }
+
+        let expected = (hll.cardinality() - 1000.0) / 1000.0;
+        assert_eq!(hll.measured_error(1000), expected);
+        assert!(hll.measured_error(1000).abs() < hll.typical_error_rate() * 3.0);
+    }
+
+    #[test]
+    fn estimate_many() {
+        let sketches = (1..4).map(|n| HyperLogLog::from_iter_with(10, 0..n * 100).unwrap())
+            .collect::<Vec<_>>();
+        let estimates = HyperLogLog::estimate_many(&sketches);
+        assert_eq!(estimates, sketches.iter().map(|s| s.cardinality()).collect::<Vec<_>>());
+        assert!(HyperLogLog::estimate_many(&[]).is_empty());
+    }
+
+    #[test]
+    fn relative_difference() {
+        let a = HyperLogLog::from_iter_with(12, 0..2000).unwrap();
+        let b = HyperLogLog::from_iter_with(10, 0..1000).unwrap();
+        assert_eq!(a.relative_difference(&b), Some((a.cardinality() - b.cardinality()) / b.cardinality()));
+        assert!((a.relative_difference(&b).unwrap() - 1.0).abs() < 0.2);
+
+        let empty = HyperLogLog::new(10).unwrap();
+        assert_eq!(a.relative_difference(&empty), None);
+        assert_eq!(empty.relative_difference(&empty), None);
+        assert_eq!(empty.relative_difference(&b), Some(-1.0));
+    }
+
+    #[test]
+    fn verify() {
+        let mut hll = HyperLogLog::new(8).unwrap();
+        hll.insert(&"test");
+        assert!(hll.verify().is_ok());
+
+        hll.registers[3] = 58;
+        assert!(hll.verify().is_err());
+        hll.registers[3] = 57;
+        assert!(hll.verify().is_ok());
+
+        hll.registers.pop();
+        assert!(hll.verify().is_err());
+
+        let mut hll = HyperLogLog::new(8).unwrap();
+        hll.alpha = 0.5;
+        assert!(hll.verify().is_err());
+    }
+
+    #[test]
+    fn from_parts() {
+        let hll = HyperLogLog::from_iter_with(8, 0..1000).unwrap();
+        let (key0, key1) = hll.keys();
+        let registers = hll.registers.clone();
+        let ptr = registers.as_ptr();
+
+        let restored = HyperLogLog::from_parts(8, key0, key1, registers).unwrap();
+        assert_eq!(restored.registers.as_ptr(), ptr);
+        assert!(restored.compatible_with(&hll));
+        assert_eq!(restored.cardinality(), hll.cardinality());
+
+        assert!(HyperLogLog::from_parts(8, key0, key1, vec![0; 255]).is_err());
+        assert!(HyperLogLog::from_parts(8, key0, key1, vec![58; 256]).is_err());
+        assert!(HyperLogLog::from_parts(3, key0, key1, vec![0; 8]).is_err());
+    }
+
+    #[test]
+    fn with_error_rate_reported() {
+        let (hll, achieved) = HyperLogLog::with_error_rate_reported(0.02).unwrap();
+        assert_eq!(hll.b, 12);
+        assert_eq!(achieved, hll.typical_error_rate());
+        assert!(achieved <= 0.02);
+
+        let (hll, _) = HyperLogLog::with_error_rate_reported(0.5).unwrap();
+        assert_eq!(hll.b, 4);
+
+        assert!(HyperLogLog::with_error_rate_reported(0.001).is_err());
+        assert!(HyperLogLog::with_error_rate_reported(0.0).is_err());
+        assert!(HyperLogLog::with_error_rate_reported(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn try_cardinality() {
+        let mut hll = HyperLogLog::new(10).unwrap();
+        assert_eq!(hll.try_cardinality().unwrap(), 0.0);
+        for i in 0..1000 {
+            hll.insert(&i);
+        }
+        assert_eq!(hll.try_cardinality().unwrap(), hll.cardinality());
+
+        for j in 0..hll.m {
+            hll.set_register(j, max_register_value(HashWidth::Bits64, 10)).unwrap();
+        }
+        assert_eq!(hll.try_cardinality().unwrap(), HashWidth::Bits64.space());
+    }
+
+    // 上限を超えた値をレジスタに直接書き込んでも、見積もり値は有限になる
+    #[cfg(feature = "advanced")]
+    #[test]
+    fn try_cardinality_with_out_of_range_registers() {
+        let mut hll = HyperLogLog::new(4).unwrap();
+        for x in hll.registers_mut() {
+            *x = u8::MAX;
+        }
+        assert_eq!(hll.try_cardinality().unwrap(), HashWidth::Bits64.space());
+    }
+
+    #[test]
+    fn saturated() {
+        let mut hll = HyperLogLog::new(10).unwrap();
+        assert_eq!(hll.cardinality_with_method().1, Estimator::LinerCounting);
+
+        for j in 0..hll.m {
+            hll.set_register(j, max_register_value(HashWidth::Bits64, 10)).unwrap();
+        }
+        let (est, method) = hll.cardinality_with_method();
+        assert_eq!(method, Estimator::Saturated);
+        assert_eq!(est, HashWidth::Bits64.space());
+
+        // レジスタの値に上限を設けたスケッチは、上限が小さいほど早く飽和する
+        let mut capped = HyperLogLog::with_register_cap(4, 2).unwrap();
+        for i in 0..1000 {
+            capped.insert(&i);
+        }
+        let (est, method) = capped.cardinality_with_method();
+        assert_eq!(method, Estimator::Saturated);
+        assert!(est.is_finite() && est < 1000.0);
+    }
+
+    #[test]
+    fn set_keys() {
+        let mut hll = HyperLogLog::new(10).unwrap();
+        hll.set_keys(1, 2).unwrap();
+        assert_eq!(hll.keys(), (1, 2));
+        let mut expected = HyperLogLog::with_parameters(10, 1, 2, HashAlgorithm::SipHash24, HashWidth::Bits64).unwrap();
+        for i in 0..100 {
+            hll.insert(&i);
+            expected.insert(&i);
+        }
+        assert_eq!(hll.registers, expected.registers);
+
+        assert!(hll.set_keys(3, 4).is_err());
+        assert_eq!(hll.keys(), (1, 2));
+    }
+
+    #[test]
+    fn all_estimates() {
+        let mut hll = HyperLogLog::new(10).unwrap();
+        let estimates = hll.all_estimates();
+        assert_eq!(estimates.iter().map(|e| e.0).collect::<Vec<_>>(),
+                   vec![Estimator::HyperLogLog, Estimator::LinerCounting]);
+        assert_eq!(estimates[1].1, 0.0);
+
+        for i in 0..500 {
+            hll.insert(&i);
+        }
+        // スモールレンジでは選ばれた見積もり値が`Linear Counting`の値と一致する
+        let (est, method) = hll.cardinality_with_method();
+        assert_eq!(method, Estimator::LinerCounting);
+        assert_eq!(hll.all_estimates()[1], (Estimator::LinerCounting, est));
+
+        for i in 0..100000 {
+            hll.insert(&i);
+        }
+        let estimates = hll.all_estimates();
+        assert_eq!(estimates, vec![(Estimator::HyperLogLog, hll.cardinality())]);
+
+        let narrow = HyperLogLog::with_parameters(10, 1, 2, HashAlgorithm::SipHash24, HashWidth::Bits32).unwrap();
+        let methods = narrow.all_estimates().iter().map(|e| e.0).collect::<Vec<_>>();
+        assert_eq!(methods, vec![Estimator::HyperLogLog, Estimator::LinerCounting, Estimator::LargeRange]);
+    }
+
+    #[test]
+    fn regime_margin() {
+        let mut hll = HyperLogLog::new(8).unwrap();
+        // 空のスケッチの未補正の見積もり値はα * m
+        let alpha: Float = hll.alpha;
+        assert!((hll.regime_margin() - (alpha as f64 - 2.5) * 256.0).abs() < 1e-3);
+        assert_eq!(hll.cardinality_with_method().1, Estimator::LinerCounting);
+        for i in 0..10000 {
+            hll.insert(&i);
+        }
+        assert!(hll.regime_margin() > 0.0);
+        assert_eq!(hll.cardinality_with_method().1, Estimator::HyperLogLog);
+    }
+
+    #[test]
+    fn confidence() {
+        // 見積もり値が切り替え点の付近に来るように、キーを固定する
+        let mut hll = HyperLogLog::with_seed(8, 1).unwrap();
+        assert_eq!(hll.confidence(), 1.0);
+
+        // 切り替え点(5m/2 = 640)付近では下がる
+        for i in 0..640 {
+            hll.insert(&i);
+        }
+        let near_boundary = hll.confidence();
+        assert!((0.5..1.0).contains(&near_boundary), "{}", near_boundary);
+
+        for i in 640..100000 {
+            hll.insert(&i);
+        }
+        assert_eq!(hll.confidence(), 1.0);
+
+        // 上限に達したレジスタが増えると下がり、すべて達すると0になる
+        let mut capped = HyperLogLog::with_register_cap(8, 3).unwrap();
+        capped.set_keys(hll.hasher_key0, hll.hasher_key1).unwrap();
+        for i in 0..200 {
+            capped.insert(&i);
+        }
+        let partly_capped = capped.confidence();
+        assert!(partly_capped > 0.0 && partly_capped < 1.0, "{}", partly_capped);
+        for i in 200..100000 {
+            capped.insert(&i);
+        }
+        assert_eq!(capped.confidence(), 0.0);
+    }
+
+    #[test]
+    fn register_cap() {
+        assert!(HyperLogLog::with_register_cap(10, 0).is_err());
+        assert!(HyperLogLog::with_register_cap(10, 56).is_err());
+
+        let mut capped = HyperLogLog::with_register_cap(4, 3).unwrap();
+        let mut uncapped = HyperLogLog::new(4).unwrap();
+        uncapped.hasher_key0 = capped.hasher_key0;
+        uncapped.hasher_key1 = capped.hasher_key1;
+        for i in 0..10000 {
+            capped.insert(&i);
+            uncapped.insert(&i);
+        }
+        assert!(capped.registers.iter().all(|&x| x <= 3));
+        assert!(capped.verify().is_ok());
+        assert!(capped.set_register(0, 4).is_err());
+        // 切り詰めた分だけ過小評価になる
+        assert!(capped.cardinality() < uncapped.cardinality());
+
+        let mut merged = capped.clone();
+        merged.merge(&uncapped).unwrap();
+        assert_eq!(merged.registers, capped.registers);
+    }
+
+    #[test]
+    fn cardinality_approx() {
+        let hll = HyperLogLog::from_iter_with(16, 0..500000).unwrap();
+        assert_eq!(hll.cardinality_approx(1.0), hll.cardinality());
+
+        let approx = hll.cardinality_approx(1.0 / 16.0);
+        assert!((approx - 500000.0).abs() < 500000.0 * 1.04 / 64.0 * 4.0, "{}", approx);
+
+        // 非常に小さい割合でもb - k = 4までしか間引かない
+        let coarse = hll.cardinality_approx(1e-9);
+        assert!(coarse.is_finite() && coarse > 0.0);
+    }
+
+    #[test]
+    fn from_sorted_hashes() {
+        let template = HyperLogLog::new(10).unwrap();
+        let (key0, key1) = template.keys();
+        let mut hashes = (0..5000).chain(0..2000).map(|i| template.hash(&i)).collect::<Vec<_>>();
+        hashes.sort();
+
+        let hll = HyperLogLog::from_sorted_hashes(10, key0, key1, hashes.iter().cloned()).unwrap();
+        let mut expected = template.clone();
+        for i in 0..5000 {
+            expected.insert(&i);
+        }
+        assert_eq!(hll.registers, expected.registers);
+        assert!(hll.compatible_with(&template));
+        assert!(HyperLogLog::from_sorted_hashes(3, key0, key1, hashes.into_iter()).is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)] // SipHasherがRust1.13.0で非推奨(deprecated)のため
+    fn insert_with_hasher() {
+        let mut hll1 = HyperLogLog::new(8).unwrap();
+        let mut hll2 = hll1.clone();
+        let (key0, key1) = hll1.keys();
+        for i in 0..1000 {
+            hll1.insert(&i);
+            hll2.insert_with_hasher(&i, SipHasher::new_with_keys(key0, key1));
+        }
+        assert_eq!(hll1.registers, hll2.registers);
+    }
+
+    #[test]
+    fn insert_and_collect_novel() {
+        let mut hll = HyperLogLog::new(8).unwrap();
+        let mut novel = Vec::new();
+        for i in 0..1000 {
+            hll.insert_and_collect_novel(&i, &mut novel);
+        }
+        assert!(!novel.is_empty() && novel.len() < 1000);
+
+        // 追加済みの要素は抜き出されない
+        let collected = novel.len();
+        for i in 0..1000 {
+            hll.insert_and_collect_novel(&i, &mut novel);
+        }
+        assert_eq!(novel.len(), collected);
+    }
+
+    #[test]
+    fn insert_batch_bytes() {
+        let items = (0..1000u32).map(|i| i.to_string().into_bytes()).collect::<Vec<_>>();
+        let slices = items.iter().map(|x| x.as_slice()).collect::<Vec<_>>();
+
+        let mut hll = HyperLogLog::new(8).unwrap();
+        let mut expected = hll.clone();
+        let changed = hll.insert_batch_bytes(&slices);
+        assert!(0 < changed && changed <= slices.len());
+
+        for x in &slices {
+            expected.insert(x);
+        }
+        assert_eq!(hll.registers, expected.registers);
+
+        // 同じ要素を再び追加してもレジスタは変化しない
+        assert_eq!(hll.insert_batch_bytes(&slices), 0);
+    }
+
+    #[test]
+    fn insert_opt() {
+        let mut hll = HyperLogLog::new(8).unwrap();
+        let mut expected = hll.clone();
+        for x in &[Some(1), None, Some(2), None] {
+            hll.insert_opt(x.as_ref());
+        }
+        expected.insert(&1);
+        expected.insert(&2);
+        assert_eq!(hll.registers, expected.registers);
+    }
+
+    #[test]
+    fn insert_cow() {
+        let v1 = HyperLogLog::new(8).unwrap();
+        let v2 = v1.insert_cow(&"a").unwrap();
+        assert_eq!(v1.registers.iter().filter(|&&x| x != 0).count(), 0);
+        let mut expected = v1.clone();
+        expected.insert(&"a");
+        assert_eq!(v2.registers, expected.registers);
+        assert!(v2.insert_cow(&"a").is_none());
+    }
+
+    #[test]
+    fn insert_debug() {
+        let mut hll = HyperLogLog::new(8).unwrap();
+        let (j, p1) = hll.insert_debug(&"test");
+        assert!(j < hll.m);
+        assert!(p1 >= 1 && p1 <= max_register_value(HashWidth::Bits64, 8));
+        assert_eq!(hll.get_register(j), Some(p1));
+
+        // 同じ要素は同じレジスタに振り分けられる
+        assert_eq!(hll.insert_debug(&"test"), (j, p1));
+    }
+
+    #[test]
+    fn from_iter_with() {
+        let hll = HyperLogLog::from_iter_with(10, 0..300).unwrap();
+        assert_eq!(hll.b, 10);
+        assert!((hll.cardinality() - 300.0).abs() < 300.0 * 0.1);
+
+        assert!(HyperLogLog::from_iter_with(3, 0..300).is_err());
+    }
+
+    #[test]
+    fn build_with_stats() {
+        let items = (0..3000).map(|i| i % 1000);
+        let (hll, total) = HyperLogLog::build_with_stats(10, items).unwrap();
+        assert_eq!(total, 3000);
+        // 重複した要素はレジスタを変えないので、異なる要素だけを追加したスケッチと一致する
+        let mut expected = HyperLogLog::new(10).unwrap();
+        let (key0, key1) = hll.keys();
+        expected.set_keys(key0, key1).unwrap();
+        for i in 0..1000 {
+            expected.insert(&i);
+        }
+        assert_eq!(hll.registers, expected.registers);
+
+        let (empty, total) = HyperLogLog::build_with_stats(10, Vec::<u32>::new()).unwrap();
+        assert_eq!(total, 0);
+        assert!(empty.is_empty());
+        assert!(HyperLogLog::build_with_stats(3, 0..10).is_err());
+    }
+
+    #[test]
+    fn merge() {
+        let mut hll1 = HyperLogLog::new(10).unwrap();
+        let mut hll2 = hll1.clone();
+        let mut expected = hll1.clone();
+        for i in 0..500 {
+            hll1.insert(&i);
+            expected.insert(&i);
+        }
+        for i in 250..1000 {
+            hll2.insert(&i);
+            expected.insert(&i);
+        }
+
+        hll1.merge(&hll2).unwrap();
+        assert_eq!(hll1.registers, expected.registers);
+
+        assert!(hll1.merge(&HyperLogLog::new(10).unwrap()).is_err());
+        assert!(hll1.merge(&HyperLogLog::new(11).unwrap()).is_err());
+    }
+
+    /// ランダムなレジスタの状態を持つ、互いにマージ可能なスケッチを作る
+    fn random_sketches(b: u8, n: usize) -> Vec<HyperLogLog> {
+        let mut rng = rand::thread_rng();
+        let template = HyperLogLog::new(b).unwrap();
+        (0..n).map(|_| {
+            let mut hll = template.clone();
+            for j in 0..hll.m {
+                hll.set_register(j, rng.gen_range(0, 20)).unwrap();
+            }
+            hll
+        }).collect()
+    }
+
+    #[test]
+    fn merge_is_commutative_and_associative() {
+        for _ in 0..10 {
+            let sketches = random_sketches(8, 3);
+            let (a, b, c) = (&sketches[0], &sketches[1], &sketches[2]);
+
+            let mut ab = a.clone();
+            ab.merge(b).unwrap();
+            let mut ba = b.clone();
+            ba.merge(a).unwrap();
+            assert_eq!(ab.registers, ba.registers);
+            assert_eq!(ab.cardinality(), ba.cardinality());
+
+            // (a ∪ b) ∪ c == a ∪ (b ∪ c)
+            let mut ab_c = ab.clone();
+            ab_c.merge(c).unwrap();
+            let mut bc = b.clone();
+            bc.merge(c).unwrap();
+            let mut a_bc = a.clone();
+            a_bc.merge(&bc).unwrap();
+            assert_eq!(ab_c.registers, a_bc.registers);
+            assert_eq!(ab_c.cardinality(), a_bc.cardinality());
+        }
+    }
+
+    #[test]
+    fn merge_with_different_register_caps_depends_on_direction() {
+        let mut capped = HyperLogLog::with_register_cap(8, 3).unwrap();
+        let mut uncapped = HyperLogLog::new(8).unwrap();
+        uncapped.hasher_key0 = capped.hasher_key0;
+        uncapped.hasher_key1 = capped.hasher_key1;
+        capped.set_register(0, 2).unwrap();
+        uncapped.set_register(0, 10).unwrap();
+
+        let mut into_capped = capped.clone();
+        into_capped.merge(&uncapped).unwrap();
+        let mut into_uncapped = uncapped.clone();
+        into_uncapped.merge(&capped).unwrap();
+
+        // 受け取る側の上限が適用される
+        assert_eq!(into_capped.get_register(0), Some(3));
+        assert_eq!(into_uncapped.get_register(0), Some(10));
+    }
+
+    #[test]
+    fn cached_cardinality() {
+        let mut hll1 = HyperLogLog::new(10).unwrap();
+        let mut hll2 = hll1.clone();
+        assert_eq!(hll1.cardinality(), 0.0);
+
+        for i in 0..500 {
+            hll1.insert(&i);
+        }
+        assert_eq!(hll1.cardinality(), estimate_cardinality(&hll1).0);
+        assert_eq!(hll1.cardinality(), estimate_cardinality(&hll1).0);
+
+        for i in 500..1000 {
+            hll2.insert(&i);
+        }
+        hll1.merge(&hll2).unwrap();
+        assert_eq!(hll1.cardinality(), estimate_cardinality(&hll1).0);
+
+        // レジスタを直接書き換えた場合は手動でキャッシュを破棄する
+        hll1.registers[0] = 40;
+        hll1.invalidate_cache();
+        assert_eq!(hll1.cardinality(), estimate_cardinality(&hll1).0);
+    }
+
+    #[test]
+    fn register_diff() {
+        let mut hll1 = HyperLogLog::new(8).unwrap();
+        let mut hll2 = hll1.clone();
+        assert!(hll1.register_diff(&hll2).unwrap().is_empty());
+
+        hll1.registers[3] = 5;
+        hll2.registers[200] = 2;
+        assert_eq!(hll1.register_diff(&hll2).unwrap(), vec![(3, 5, 0), (200, 0, 2)]);
+
+        assert!(hll1.register_diff(&HyperLogLog::new(8).unwrap()).is_err());
+    }
+
+    #[test]
+    fn merge_with_margin() {
+        let mut hll1 = HyperLogLog::new(4).unwrap();
+        let mut hll2 = hll1.clone();
+        hll1.registers[..3].copy_from_slice(&[2, 2, 2]);
+        hll2.registers[..3].copy_from_slice(&[3, 4, 5]);
+
+        let mut zero = hll1.clone();
+        zero.merge_with_margin(&hll2, 0).unwrap();
+        let mut merged = hll1.clone();
+        merged.merge(&hll2).unwrap();
+        assert_eq!(zero.registers, merged.registers);
+
+        hll1.merge_with_margin(&hll2, 2).unwrap();
+        assert_eq!(&hll1.registers[..3], &[2, 2, 5]);
+        assert!(hll1.merge_with_margin(&HyperLogLog::new(4).unwrap(), 1).is_err());
+    }
+
+    #[test]
+    fn merge_delta() {
+        let mut child = HyperLogLog::from_iter_with(10, 0..1000).unwrap();
+        let mut parent = child.clone();
+        let before = child.clone();
+        for i in 1000..1100 {
+            child.insert(&i);
+        }
+        let changed = before.register_diff(&child).unwrap().iter().map(|&(j, _, _)| j).collect::<Vec<_>>();
+        let mut expected = parent.clone();
+        expected.merge(&child).unwrap();
+        parent.merge_delta(&child, &changed).unwrap();
+        assert_eq!(parent.registers, expected.registers);
+        assert_eq!(parent.cardinality(), expected.cardinality());
+
+        assert!(parent.merge_delta(&child, &[1024]).is_err());
+        assert!(parent.merge_delta(&HyperLogLog::new(10).unwrap(), &[0]).is_err());
+    }
+
+    #[test]
+    fn pairwise_intersections() {
+        let template = HyperLogLog::new(14).unwrap();
+        let mut sketches = [template.clone(), template.clone(), template.clone()];
+        for i in 0..20000 {
+            sketches[0].insert(&i);
+            sketches[1].insert(&(i + 10000));
+            sketches[2].insert(&(i + 100000));
+        }
+        let refs = sketches.iter().collect::<Vec<_>>();
+        let matrix = HyperLogLog::pairwise_intersections(&refs).unwrap();
+        assert_eq!(matrix.len(), 3);
+        for (i, row) in matrix.iter().enumerate() {
+            assert_eq!(row[i], sketches[i].cardinality());
+            assert!(row.iter().enumerate().all(|(j, &x)| x == matrix[j][i]));
+        }
+        assert!((matrix[0][1] - 10000.0).abs() < 10000.0 * 0.1, "{}", matrix[0][1]);
+        assert!(matrix[0][2] < 2000.0, "{}", matrix[0][2]);
+
+        assert!(HyperLogLog::pairwise_intersections(&[]).unwrap().is_empty());
+        let other = HyperLogLog::new(14).unwrap();
+        assert!(HyperLogLog::pairwise_intersections(&[&sketches[0], &other]).is_err());
+    }
+
+    #[test]
+    fn max_minus() {
+        let empty = HyperLogLog::new(10).unwrap();
+        let mut a = empty.clone();
+        let mut b = empty.clone();
+        for i in 0..5000 {
+            a.insert(&i);
+            b.insert(&(i + 2500));
+        }
+        let diff = a.max_minus(&b).unwrap();
+        for j in 0..a.m {
+            assert_eq!(diff.registers[j], a.registers[j].saturating_sub(b.registers[j]));
+        }
+        assert!(a.max_minus(&a).unwrap().is_empty());
+        assert_eq!(a.max_minus(&empty).unwrap().registers, a.registers);
+        assert!(a.max_minus(&HyperLogLog::new(10).unwrap()).is_err());
+    }
+
+    #[test]
+    fn jaccard_matrix() {
+        let template = HyperLogLog::new(14).unwrap();
+        let mut sketches = [template.clone(), template.clone(), template.clone(), template.clone()];
+        for i in 0..20000 {
+            sketches[0].insert(&i);
+            sketches[1].insert(&(i + 10000));
+            sketches[2].insert(&(i + 100000));
+        }
+        let refs = sketches.iter().collect::<Vec<_>>();
+        let matrix = HyperLogLog::jaccard_matrix(&refs).unwrap();
+        assert_eq!(matrix.len(), 4);
+        for (i, row) in matrix.iter().enumerate() {
+            assert_eq!(row[i], 1.0);
+            assert!(row.iter().enumerate().all(|(j, &x)| x == matrix[j][i] && (0.0..=1.0).contains(&x)));
+        }
+        // |A ∩ B| = 10000, |A ∪ B| = 30000
+        assert!((matrix[0][1] - 1.0 / 3.0).abs() < 0.05, "{}", matrix[0][1]);
+        assert!(matrix[0][2] < 0.05, "{}", matrix[0][2]);
+        assert_eq!(matrix[0][3], 0.0);
+
+        let empty = [&sketches[3], &sketches[3]];
+        assert_eq!(HyperLogLog::jaccard_matrix(&empty).unwrap(), vec![vec![1.0; 2]; 2]);
+        let other = HyperLogLog::new(14).unwrap();
+        assert!(HyperLogLog::jaccard_matrix(&[&sketches[0], &other]).is_err());
+    }
+
+    #[test]
+    fn reduce() {
+        assert!(HyperLogLog::reduce(Vec::new().into_iter()).unwrap().is_none());
+
+        let template = HyperLogLog::new(10).unwrap();
+        let mut expected = template.clone();
+        let sketches = (0..5).map(|n| {
+            let mut hll = template.clone();
+            for i in (n * 100)..(n * 100 + 200) {
+                hll.insert(&i);
+                expected.insert(&i);
+            }
+            hll
+        });
+        let union = HyperLogLog::reduce(sketches).unwrap().unwrap();
+        assert_eq!(union.registers, expected.registers);
+
+        let mixed = vec![template.clone(), HyperLogLog::new(10).unwrap()];
+        assert!(HyperLogLog::reduce(mixed.into_iter()).is_err());
+    }
+
+    #[test]
+    fn empty_like() {
+        let mut hll = HyperLogLog::with_register_cap(10, 5).unwrap();
+        for i in 0..1000 {
+            hll.insert(&i);
+        }
+        let mut empty = hll.empty_like();
+        assert!(empty.compatible_with(&hll));
+        assert_eq!(empty.register_cap, 5);
+        assert!(empty.registers.iter().all(|&x| x == 0));
+        assert_eq!(empty.cardinality(), 0.0);
+        empty.merge(&hll).unwrap();
+        assert_eq!(empty.registers, hll.registers);
+    }
+
+    #[test]
+    fn compatibility_report() {
+        let base = HyperLogLog::new(10).unwrap();
+        let other_keys = HyperLogLog::new(10).unwrap();
+        let other_b = base.empty_with_b(8).unwrap();
+        let report = HyperLogLog::compatibility_report(&[&base, &base.clone(), &other_keys, &other_b]);
+        assert_eq!(report.compatible_indices(), vec![0, 1]);
+        assert_eq!(report.incompatible_indices(), vec![2, 3]);
+        assert!(!report.all_compatible());
+        assert_eq!(report.sketches[2], SketchCompatibility { b_matches: true, keys_match: false, hash_matches: true });
+        assert_eq!(report.sketches[3], SketchCompatibility { b_matches: false, keys_match: true, hash_matches: true });
+
+        assert!(HyperLogLog::compatibility_report(&[]).all_compatible());
+    }
+
+    #[test]
+    fn compatible_with() {
+        let hll1 = HyperLogLog::new(10).unwrap();
+        let hll2 = hll1.clone();
+        assert_eq!(hll1.keys(), hll2.keys());
+        assert!(hll1.compatible_with(&hll2));
+
+        let hll3 = HyperLogLog::new(10).unwrap();
+        assert_ne!(hll1.keys(), hll3.keys());
+        assert!(!hll1.compatible_with(&hll3));
+        assert!(!hll1.compatible_with(&HyperLogLog::new(11).unwrap()));
+    }
+
+    #[test]
+    fn siphash13() {
+        // 精度の検査を再現できるように、キーを固定する
+        let mut hll = HyperLogLog::with_siphash13(12).unwrap();
+        hll.set_keys(0x0123_4567_89ab_cdef, 0xfedc_ba98_7654_3210).unwrap();
+        assert_eq!(hll.hash_algorithm(), HashAlgorithm::SipHash13);
+        for i in 0..100000 {
+            hll.insert(&i);
+        }
+        assert!(hll.measured_error(100000).abs() < hll.typical_error_rate() * 4.0);
+
+        // キーが同じでもアルゴリズムが異なればマージできない
+        let mut default = HyperLogLog::new(12).unwrap();
+        default.hasher_key0 = hll.hasher_key0;
+        default.hasher_key1 = hll.hasher_key1;
+        assert!(!default.compatible_with(&hll));
+        assert!(default.merge(&hll).is_err());
+
+        // キーも異なる場合、エラーは両方のアルゴリズムを示す
+        let err = HyperLogLog::new(12).unwrap().merge(&hll).unwrap_err().to_string();
+        assert!(err.contains("SipHash24") && err.contains("SipHash13"), "{}", err);
+    }
+
+    #[test]
+    fn upscale_view() {
+        let mut hll = HyperLogLog::new(8).unwrap();
+        for i in 0..1000 {
+            hll.insert(&i);
+        }
+        hll.registers[5] = max_register_value(HashWidth::Bits64, 8);
+
+        let view = hll.upscale_view(10).unwrap();
+        assert_eq!(view.b, 10);
+        assert_eq!(view.keys(), hll.keys());
+        assert!(view.verify().is_ok());
+        for j in 0..view.m {
+            let parent = hll.registers[j % hll.m];
+            assert_eq!(view.registers[j], parent.saturating_sub(2).min(max_register_value(HashWidth::Bits64, 10)));
+        }
+
+        assert_eq!(hll.upscale_view(8).unwrap().registers, hll.registers);
+        assert!(hll.upscale_view(7).is_err());
+        assert!(hll.upscale_view(17).is_err());
+    }
+
+    #[test]
+    fn upscale_view_preserves_cardinality() {
+        let mut hll = HyperLogLog::with_seed(10, 1).unwrap();
+        for i in 0..50000 {
+            hll.insert(&i);
+        }
+        let estimate = hll.cardinality();
+        let upscaled = hll.upscale_view(12).unwrap().cardinality();
+        assert!((upscaled - estimate).abs() < estimate * hll.typical_error_rate() * 3.0,
+                "estimate = {}, upscaled = {}", estimate, upscaled);
+    }
+
+    #[test]
+    fn get_and_set_register() {
+        let mut hll = HyperLogLog::new(4).unwrap();
+        assert_eq!(hll.get_register(15), Some(0));
+        assert_eq!(hll.get_register(16), None);
+        assert_eq!(hll.cardinality(), 0.0);
+
+        hll.set_register(15, 61).unwrap();
+        assert_eq!(hll.get_register(15), Some(61));
+        assert!(hll.cardinality() > 0.0);
+
+        assert!(hll.set_register(16, 1).is_err());
+        assert!(hll.set_register(0, 62).is_err());
+        assert!(hll.verify().is_ok());
+    }
+
+    #[test]
+    fn to_prometheus() {
+        let hll = HyperLogLog::new(4).unwrap();
+        assert_eq!(hll.to_prometheus("users"), "\
+# HELP users_estimated_cardinality Estimated number of distinct elements.
+# TYPE users_estimated_cardinality gauge
+users_estimated_cardinality 0
+# HELP users_registers Number of registers (2^b).
+# TYPE users_registers gauge
+users_registers 16
+# HELP users_zero_registers Number of registers that have never been updated.
+# TYPE users_zero_registers gauge
+users_zero_registers 16
+# HELP users_saturated Whether the sketch is saturated (1) or not (0).
+# TYPE users_saturated gauge
+users_saturated 0
+");
+
+        let mut saturated = hll.clone();
+        for j in 0..16 {
+            saturated.set_register(j, max_register_value(HashWidth::Bits64, 4)).unwrap();
+        }
+        assert!(saturated.is_saturated());
+        assert!(saturated.to_prometheus("users").ends_with("\
+# TYPE users_saturated gauge
+users_saturated 1
+"));
+    }
+
+    #[test]
+    fn new_elements_since() {
+        let mut hll = HyperLogLog::from_iter_with(12, 0..10000).unwrap();
+        let snapshot = hll.clone();
+        assert_eq!(hll.new_elements_since(&snapshot).unwrap(), 0.0);
+        for i in 10000..15000 {
+            hll.insert(&i);
+        }
+        let added = hll.new_elements_since(&snapshot).unwrap();
+        assert!((added - 5000.0).abs() < 15000.0 * hll.typical_error_rate() * 4.0, "{}", added);
+        assert_eq!(snapshot.new_elements_since(&hll).unwrap(), 0.0);
+        assert!(hll.new_elements_since(&HyperLogLog::new(12).unwrap()).is_err());
+    }
+
+    #[test]
+    fn union_error_estimate() {
+        let mut a = HyperLogLog::new(10).unwrap();
+        let mut b = a.clone();
+        assert_eq!(a.union_error_estimate(&b).unwrap(), 0.0);
+
+        for i in 0..200 {
+            a.insert(&i);
+            b.insert(&(i + 100));
+        }
+        // 小さな和集合は`Linear Counting`で見積もられ、典型的なエラー率より誤差が小さい
+        let small = a.union_error_estimate(&b).unwrap();
+        assert!(0.0 < small && small < a.typical_error_rate());
+
+        for i in 0..100000 {
+            b.insert(&i);
+        }
+        assert_eq!(a.union_error_estimate(&b).unwrap(), a.typical_error_rate());
+        assert!(a.union_error_estimate(&HyperLogLog::new(10).unwrap()).is_err());
+    }
+
+    #[test]
+    fn caching() {
+        let mut hll = HyperLogLog::new(10).unwrap();
+        for i in 0..1000 {
+            hll.insert(&i);
+        }
+        // 既定ではキャッシュする。2回目は1回目に計算した値をそのまま返す
+        let first = hll.cardinality();
+        assert_eq!(hll.cached_estimate.load(Ordering::Relaxed), first.to_bits());
+        assert_eq!(hll.cardinality().to_bits(), first.to_bits());
+        // 要素の追加ではレジスタが変化しないことがあるので、確実に値の変わる書き込みで確かめる
+        let ceiling = max_register_value(HashWidth::Bits64, 10);
+        assert_ne!(hll.registers[0], ceiling);
+        hll.set_register(0, ceiling).unwrap();
+        assert_eq!(hll.cached_estimate.load(Ordering::Relaxed), CACHE_INVALID);
+
+        hll.set_caching(false);
+        let fresh = hll.cardinality();
+        assert_eq!(hll.cached_estimate.load(Ordering::Relaxed), CACHE_INVALID);
+        assert_eq!(fresh, estimate_cardinality(&hll).0);
+        assert!(!hll.empty_like().caching);
+
+        hll.set_caching(true);
+        assert_eq!(hll.cardinality(), fresh);
+        assert_eq!(hll.cached_estimate.load(Ordering::Relaxed), fresh.to_bits());
+    }
+
+    #[test]
+    fn clamp_to_capacity() {
+        let mut hll = HyperLogLog::with_register_cap(10, 3).unwrap();
+        assert_eq!(hll.capacity(), 4096.0);
+        assert_eq!(HyperLogLog::new(10).unwrap().capacity(), 2f64.powi(64));
+        assert_eq!(HyperLogLog::with_32bit_hash(10).unwrap().capacity(), 2f64.powi(32));
+
+        for i in 0..100000 {
+            hll.insert(&i);
+        }
+        assert!(hll.is_saturated());
+        assert!(hll.cardinality() > hll.capacity());
+        hll.set_clamp_to_capacity(true);
+        assert_eq!(hll.cardinality(), hll.capacity());
+        assert!(hll.is_saturated());
+
+        let mut hll = HyperLogLog::from_iter_with(10, 0..1000).unwrap();
+        let est = hll.cardinality();
+        hll.set_clamp_to_capacity(true);
+        assert_eq!(hll.cardinality(), est);
+        assert!(!hll.is_saturated());
+    }
+
+    #[test]
+    fn cardinality_with_alpha() {
+        let hll = HyperLogLog::from_iter_with(10, 0..100000).unwrap();
+        let alpha: Float = hll.alpha;
+        assert_eq!(hll.cardinality_with_alpha(alpha as f64), hll.cardinality());
+        // `HyperLogLog`アルゴリズムの見積もり値はα値に比例する
+        let scaled = hll.cardinality_with_alpha(alpha as f64 * 1.01);
+        assert!((scaled / hll.cardinality() - 1.01).abs() < 1e-6);
+        assert_eq!(hll.alpha, get_alpha(10).unwrap());
+    }
+
+    #[test]
+    fn cardinality_recent() {
+        let mut hll = HyperLogLog::new(12).unwrap();
+        assert!(hll.cardinality_recent(0).is_err());
+        hll.enable_recency_tracking();
+        for i in 0..20000 {
+            hll.insert(&i);
+        }
+        assert_eq!(hll.insert_sequence(), 20000);
+        assert_eq!(hll.cardinality_recent(0).unwrap(), hll.cardinality());
+
+        let seq = hll.insert_sequence();
+        assert_eq!(hll.cardinality_recent(seq).unwrap(), 0.0);
+        for i in 20000..21000 {
+            hll.insert(&i);
+        }
+        // 値を増やさなかった要素は数えないので過小評価になる
+        let recent = hll.cardinality_recent(seq).unwrap();
+        assert!(recent > 0.0 && recent < 1000.0, "{}", recent);
+    }
+
+    #[test]
+    fn projected_saturation() {
+        let mut hll = HyperLogLog::with_register_cap(10, 4).unwrap();
+        for i in 0..1000 {
+            hll.insert(&i);
+        }
+        let remaining = hll.capacity() - hll.cardinality();
+        assert_eq!(hll.projected_saturation(1.0), Some(remaining));
+        assert_eq!(hll.projected_saturation(100.0), Some(remaining / 100.0));
+        assert_eq!(hll.projected_saturation(0.0), None);
+        assert_eq!(hll.projected_saturation(f64::NAN), None);
+
+        for i in 1000..1000000 {
+            hll.insert(&i);
+        }
+        assert_eq!(hll.projected_saturation(1.0), None);
+    }
+
+    #[test]
+    fn cardinality_f32() {
+        let hll = HyperLogLog::from_iter_with(10, 0..3000).unwrap();
+        assert_eq!(hll.cardinality_f32(), hll.cardinality() as f32);
+        assert_eq!(HyperLogLog::new(4).unwrap().cardinality_f32(), 0.0);
+    }
+
+    #[test]
+    fn register_mode() {
+        let mut hll = HyperLogLog::new(4).unwrap();
+        assert_eq!(hll.register_mode(), 0);
+        for j in 0..8 {
+            hll.set_register(j, 3).unwrap();
+        }
+        // 0と3が8個ずつなら小さい方
+        assert_eq!(hll.register_mode(), 0);
+        hll.set_register(8, 3).unwrap();
+        assert_eq!(hll.register_mode(), 3);
+
+        let mut hll = HyperLogLog::new(10).unwrap();
+        for i in 0..(1024 * 64) {
+            hll.insert(&i);
+        }
+        // log2(64) = 6の付近
+        assert!((5..=7).contains(&hll.register_mode()), "{}", hll.register_mode());
+    }
+
+    #[test]
+    fn register_value_fractions() {
+        let mut hll = HyperLogLog::new(4).unwrap();
+        assert_eq!(hll.register_value_fractions().into_iter().collect::<Vec<_>>(), vec![(0, 1.0)]);
+        for j in 0..4 {
+            hll.set_register(j, 1).unwrap();
+        }
+        hll.set_register(4, 3).unwrap();
+        let fractions = hll.register_value_fractions();
+        assert_eq!(fractions.into_iter().collect::<Vec<_>>(), vec![(0, 11.0 / 16.0), (1, 0.25), (3, 1.0 / 16.0)]);
+
+        // 最頻値はキーによって変わるので、キーを固定する
+        let mut small = HyperLogLog::with_seed(8, 1).unwrap();
+        let mut large = HyperLogLog::with_seed(12, 1).unwrap();
+        for i in 0..100000 {
+            small.insert(&i);
+            large.insert(&i);
+        }
+        let total = large.register_value_fractions().values().sum::<f64>();
+        assert!((total - 1.0).abs() < 1e-9);
+        let small_fractions = small.register_value_fractions();
+        let large_fractions = large.register_value_fractions();
+        // 同じ要素を数えると分布の形は一致し、レジスタ1個あたりの要素が16倍の
+        // 小さいスケッチの方が、最頻値が約4大きくなる
+        let peak = |f: &BTreeMap<u8, f64>| *f.iter().max_by(|a, b| a.1.partial_cmp(b.1).unwrap()).unwrap().0;
+        assert!((peak(&small_fractions) as i32 - peak(&large_fractions) as i32 - 4).abs() <= 1);
+    }
+
+    #[test]
+    fn cumulative_register_value() {
+        let mut hll = HyperLogLog::new(4).unwrap();
+        assert_eq!(hll.cumulative_register_value(0.9), 0);
+        for j in 0..8 {
+            hll.set_register(j, 1).unwrap();
+        }
+        for j in 8..15 {
+            hll.set_register(j, 2).unwrap();
+        }
+        hll.set_register(15, 7).unwrap();
+        assert_eq!(hll.cumulative_register_value(0.0), 1);
+        assert_eq!(hll.cumulative_register_value(0.5), 1);
+        assert_eq!(hll.cumulative_register_value(0.51), 2);
+        assert_eq!(hll.cumulative_register_value(0.9), 2);
+        assert_eq!(hll.cumulative_register_value(1.0), 7);
+        assert_eq!(hll.cumulative_register_value(2.0), 7);
+    }
+
+    #[test]
+    fn to_json() {
+        let hll = HyperLogLog::new(4).unwrap();
+        assert_eq!(hll.to_json(),
+                   r#"{"b":4,"m":16,"alpha":0.673,"estimate":0,"method":"LinerCounting","zero_registers":16}"#);
+    }
+
+    #[test]
+    fn sparsify_to_top() {
+        let mut hll = HyperLogLog::from_iter_with(8, 0..10000).unwrap();
+        assert!(hll.registers.iter().filter(|&&x| x != 0).count() > 20);
+        let mut top = hll.registers.clone();
+        top.sort();
+        let threshold = top[top.len() - 20];
+
+        hll.sparsify_to_top(20);
+        assert_eq!(hll.registers.iter().filter(|&&x| x != 0).count(), 20);
+        assert!(hll.registers.iter().filter(|&&x| x != 0).all(|&x| x >= threshold));
+
+        // 値が0でないレジスタがk個以下なら変化しない
+        let before = hll.registers.clone();
+        hll.sparsify_to_top(30);
+        assert_eq!(hll.registers, before);
+    }
+
+    #[test]
+    fn register_histogram_svg() {
+        let mut hll = HyperLogLog::new(4).unwrap();
+        hll.set_register(0, 3).unwrap();
+        hll.set_register(1, 1).unwrap();
+        let svg = hll.register_histogram_svg(200, 100);
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"200\" height=\"100\""));
+        assert!(svg.ends_with("</svg>\n"));
+        // 値0から3までの4本の棒を描く
+        assert_eq!(svg.matches("<rect").count(), 4);
+        assert!(svg.contains(">14</text>"));
+    }
+
+    #[cfg(feature = "advanced")]
+    #[test]
+    fn registers_mut() {
+        let mut hll = HyperLogLog::from_iter_with(8, 0..100).unwrap();
+        let before = hll.cardinality();
+        for x in hll.registers_mut() {
+            *x += 1;
+        }
+        hll.recompute_cached();
+        assert!(hll.cardinality() > before);
+        assert_eq!(hll.cardinality(), estimate_cardinality(&hll).0);
+        assert!(hll.verify().is_ok());
+    }
+
+    #[test]
+    fn fingerprint() {
+        // キーを固定し、"another test"が"test"と異なるレジスタに入ることを確かめてから比べる
+        let mut hll1 = HyperLogLog::with_seed(10, 1).unwrap();
+        let (j1, _) = hll1.insert_debug(&"test");
+        let mut hll2 = hll1.clone();
+        assert_eq!(hll1.fingerprint(), hll2.fingerprint());
+
+        let (j2, _) = hll2.insert_debug(&"another test");
+        assert_ne!(j1, j2);
+        assert_ne!(hll1.fingerprint(), hll2.fingerprint());
+
+        let mut hll3 = HyperLogLog::with_seed(10, 2).unwrap();
+        hll3.insert(&"test");
+        assert_ne!(hll1.fingerprint(), hll3.fingerprint());
+    }
+
+    #[test]
+    fn folded() {
+        let mut high = HyperLogLog::new(10).unwrap();
+        let mut low = high.empty_with_b(6).unwrap();
+        for i in 0..5000 {
+            high.insert(&i);
+            low.insert(&i);
+        }
+        // ハッシュ値の残りのビットがすべて0だった場合も数え直される
+        high.registers[0b11_0000_0001] = max_register_value(HashWidth::Bits64, 10);
+        low.registers[0b00_0001] = max_register_value(HashWidth::Bits64, 10);
+        high.registers[0b00_0000_0010] = max_register_value(HashWidth::Bits64, 10);
+        low.registers[0b00_0010] = max_register_value(HashWidth::Bits64, 6);
+        assert_eq!(high.folded(6).registers, low.registers);
+        assert_eq!(high.folded(10).registers, high.registers);
+    }
+
+    #[test]
+    fn insert_display() {
+        let empty = HyperLogLog::new(10).unwrap();
+        let mut displayed = empty.clone();
+        let mut strings = empty.clone();
+        for i in 0..1000 {
+            let x = i as f64 * 0.5;
+            displayed.insert_display(&x);
+            strings.insert(&x.to_string());
+        }
+        assert_eq!(displayed.registers, strings.registers);
+
+        // 表示が同じ値は同じ要素として数える
+        let mut hll = empty.clone();
+        hll.insert_display(&1.5f64);
+        hll.insert_display(&1.5f32);
+        hll.insert_display(&"1.5");
+        assert!((hll.cardinality() - 1.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn insert_pair() {
+        // 精度の検査を再現できるように、キーを固定する
+        let empty = HyperLogLog::with_seed(10, 1).unwrap();
+        let mut pairs = empty.clone();
+        let mut tuples = empty.clone();
+        for user in 0..100u32 {
+            for page in 0..20u64 {
+                pairs.insert_pair(&user, &format!("page{}", page));
+                tuples.insert(&(user, format!("page{}", page)));
+            }
+        }
+        assert_eq!(pairs.registers, tuples.registers);
+        assert!((tuples.cardinality() - 2000.0).abs() < 2000.0 * 0.1);
+
+        // 組の順序は区別される
+        assert_ne!(empty.hash(&(1u32, 2u32)), empty.hash(&(2u32, 1u32)));
+    }
+
+    #[test]
+    fn merge_with_empty() {
+        let empty = HyperLogLog::new(10).unwrap();
+        assert!(empty.is_empty());
+        let mut hll = empty.clone();
+        for i in 0..1000 {
+            hll.insert(&i);
+        }
+        assert!(!hll.is_empty());
+        let before = hll.cardinality();
+
+        let mut merged = hll.clone();
+        merged.merge(&empty).unwrap();
+        assert_eq!(merged.registers, hll.registers);
+        assert_eq!(merged.cardinality(), before);
+
+        let mut merged = empty.clone();
+        merged.merge(&hll).unwrap();
+        assert_eq!(merged.registers, hll.registers);
+        assert_eq!(merged.cardinality(), before);
+
+        // 空のスケッチでも互換性は検査する
+        assert!(hll.clone().merge(&HyperLogLog::new(10).unwrap()).is_err());
+        assert!(empty.clone().merge(&HyperLogLog::new(10).unwrap()).is_err());
+
+        // 上限の小さいスケッチに空でないスケッチをマージすると、上限で頭打ちになる
+        let (key0, key1) = empty.keys();
+        let mut capped = HyperLogLog::with_register_cap(10, 2).unwrap();
+        capped.set_keys(key0, key1).unwrap();
+        capped.merge(&hll).unwrap();
+        assert!(capped.registers.iter().all(|&x| x <= 2));
+    }
+
+    #[test]
+    fn merge_strict() {
+        let empty = HyperLogLog::new(10).unwrap();
+        let mut a = empty.clone();
+        let mut b = empty.clone();
+        for i in 0..1000 {
+            a.insert(&i);
+            b.insert(&(i + 500));
+        }
+        let mut expected = a.clone();
+        expected.merge(&b).unwrap();
+        let mut merged = a.clone();
+        merged.merge_strict(&b).unwrap();
+        assert_eq!(merged.registers, expected.registers);
+
+        // 上限を超える値は`merge`では切り詰められるが、`merge_strict`では検出する
+        let mut corrupted = b.clone();
+        corrupted.registers[7] = 60;
+        let mut merged = a.clone();
+        let err = merged.merge_strict(&corrupted).unwrap_err();
+        assert!(err.to_string().contains("other.registers[7]"), "{}", err);
+        assert_eq!(merged.registers, a.registers);
+        let mut merged = corrupted.clone();
+        let err = merged.merge_strict(&a).unwrap_err();
+        assert!(err.to_string().contains("self.registers[7]"), "{}", err);
+
+        assert!(a.clone().merge_strict(&HyperLogLog::new(10).unwrap()).is_err());
+    }
+
+    #[test]
+    fn merge_upscaled_accuracy() {
+        let n = 50000;
+        let mut low = HyperLogLog::with_seed(12, 1).unwrap().empty_with_b(10).unwrap();
+        let mut high = HyperLogLog::with_seed(12, 1).unwrap();
+        for i in 0..n {
+            low.insert(&i);
+            high.insert(&i);
+        }
+        let mut merged = high.clone();
+        merged.merge_upscaled(&low).unwrap();
+        let error = merged.measured_error(n);
+        assert!(error.abs() < low.typical_error_rate() * 3.0, "{}", merged.cardinality());
+
+        // 精度の高い方にしかない要素も和集合として数える
+        for i in n..2 * n {
+            high.insert(&i);
+        }
+        high.merge_upscaled(&low).unwrap();
+        let error = high.measured_error(2 * n);
+        assert!(error.abs() < low.typical_error_rate() * 3.0, "{}", high.cardinality());
+    }
+
+    #[test]
+    fn merge_upscaled() {
+        let base = HyperLogLog::new(10).unwrap();
+        let mut low = base.empty_with_b(8).unwrap();
+        let mut high = base.clone();
+        for i in 0..2000 {
+            low.insert(&i);
+            high.insert(&(i + 1000));
+        }
+
+        let expected = HyperLogLog::merge_all_folded(&[low.clone(), high.clone()], 8).unwrap()
+            .upscale_view(10).unwrap();
+
+        let mut merged = low.clone();
+        merged.merge_upscaled(&high).unwrap();
+        assert_eq!(merged.b, 10);
+        assert_eq!(merged.registers, expected.registers);
+
+        let mut merged = high.clone();
+        merged.merge_upscaled(&low).unwrap();
+        assert_eq!(merged.b, 10);
+        assert_eq!(merged.registers, expected.registers);
+
+        let mut same = high.clone();
+        same.merge_upscaled(&high).unwrap();
+        assert_eq!(same.registers, high.registers);
+
+        let mut unchanged = low.clone();
+        assert!(unchanged.merge_upscaled(&HyperLogLog::new(10).unwrap()).is_err());
+        assert_eq!(unchanged.b, 8);
+        assert_eq!(unchanged.registers, low.registers);
+    }
+
+    #[test]
+    fn merge_tree() {
+        let base = HyperLogLog::new(10).unwrap();
+        let mut sketches = Vec::new();
+        let mut expected = base.clone();
+        for k in 0..7 {
+            let mut sketch = base.clone();
+            for i in 0..200 {
+                sketch.insert(&(i + k * 150));
+                expected.insert(&(i + k * 150));
+            }
+            sketches.push(sketch);
+        }
+        let merged = HyperLogLog::merge_tree(sketches.clone()).unwrap();
+        assert_eq!(merged.registers, expected.registers);
+        assert_eq!(HyperLogLog::merge_tree(vec![base.clone()]).unwrap().registers, base.registers);
+
+        assert!(HyperLogLog::merge_tree(Vec::new()).is_err());
+        sketches.push(HyperLogLog::new(10).unwrap());
+        let err = HyperLogLog::merge_tree(sketches).unwrap_err();
+        assert!(err.to_string().contains("sketches[7]"), "{}", err);
+    }
+
+    #[test]
+    fn merge_all_folded() {
+        let base = HyperLogLog::new(12).unwrap();
+        let mut sketches = vec![base.clone(), base.empty_with_b(10).unwrap(), base.empty_with_b(8).unwrap()];
+        for (k, sketch) in sketches.iter_mut().enumerate() {
+            for i in 0..3000 {
+                sketch.insert(&(i + k * 1000));
+            }
+        }
+        let merged = HyperLogLog::merge_all_folded(&sketches, 8).unwrap();
+        let mut expected = sketches[0].folded(8);
+        expected.merge(&sketches[1].folded(8)).unwrap();
+        expected.merge(&sketches[2]).unwrap();
+        assert_eq!(merged.b, 8);
+        assert_eq!(merged.registers, expected.registers);
+
+        assert!(HyperLogLog::merge_all_folded(&sketches, 10).is_err());
+        assert!(HyperLogLog::merge_all_folded(&[], 8).is_err());
+        sketches.push(HyperLogLog::new(12).unwrap());
+        assert!(HyperLogLog::merge_all_folded(&sketches, 8).is_err());
+    }
+
+    #[test]
+    fn split() {
+        let hll = HyperLogLog::from_iter_with(12, 0..100000).unwrap();
+        assert!(hll.split(9).is_err());
+
+        let parts = hll.split(3).unwrap();
+        assert_eq!(parts.len(), 8);
+        assert!(parts.iter().all(|p| p.b == 9 && p.alpha == get_alpha(9).unwrap() && p.keys() == hll.keys()));
+        assert_eq!(parts.iter().flat_map(|p| p.registers.clone()).collect::<Vec<_>>(), hll.registers);
+
+        let total = parts.iter().map(|p| p.cardinality()).sum::<f64>();
+        assert!((total - 100000.0).abs() < 100000.0 * 0.1, "{}", total);
+
+        assert_eq!(hll.split(0).unwrap()[0].registers, hll.registers);
+    }
+
+    #[test]
+    fn hash_32bit() {
+        let mut hll = HyperLogLog::with_32bit_hash(12).unwrap();
+        assert_eq!(hll.hash_width(), HashWidth::Bits32);
+        for i in 0..100000 {
+            hll.insert(&i);
+        }
+        assert!(hll.registers.iter().all(|&x| x <= max_register_value(HashWidth::Bits32, 12)));
+        assert!(hll.measured_error(100000).abs() < hll.typical_error_rate() * 4.0);
+        assert!(hll.verify().is_ok());
+    }
+
+    #[test]
+    fn hash_width_mismatch() {
+        let mut hll32 = HyperLogLog::with_32bit_hash(10).unwrap();
+        let (key0, key1) = hll32.keys();
+        let mut hll64 = HyperLogLog::with_parameters(10, key0, key1, HashAlgorithm::SipHash24, HashWidth::Bits64)
+            .unwrap();
+        for i in 0..1000 {
+            hll32.insert(&i);
+            hll64.insert(&i);
+        }
+        // キーが同じでもハッシュ値のビット数が異なればマージできない
+        let message = hll64.clone().merge(&hll32).unwrap_err().to_string();
+        assert!(message.contains("hash widths must be the same"), "{}", message);
+        assert!(!hll64.compatible_with(&hll32));
+        assert!(!HyperLogLog::compatibility_report(&[&hll64, &hll32]).sketches[1].hash_matches);
+
+        // シリアライズしてもビット数は保たれる
+        let restored = HyperLogLog::from_bytes(&hll32.to_bytes_compressed()).unwrap();
+        assert_eq!(restored.hash_width(), HashWidth::Bits32);
+        assert!(restored.clone().merge(&hll32).is_ok());
+        assert!(hll64.merge(&restored).is_err());
+    }
+
+    #[test]
+    fn serialization() {
+        let mut hll = HyperLogLog::with_siphash13(10).unwrap();
+        for i in 0..5000 {
+            hll.insert(&i);
+        }
+        for bytes in &[hll.to_bytes(), hll.to_bytes_compressed()] {
+            let restored = HyperLogLog::from_bytes(bytes).unwrap();
+            assert_eq!(restored.registers, hll.registers);
+            assert!(restored.compatible_with(&hll));
+            assert_eq!(restored.fingerprint(), hll.fingerprint());
+        }
+        // 値が0のレジスタが少なければ圧縮しない
+        assert_eq!(hll.to_bytes_compressed(), hll.to_bytes());
+
+        let capped = HyperLogLog::with_register_cap(6, 3).unwrap();
+        assert_eq!(HyperLogLog::from_bytes(&capped.to_bytes()).unwrap().register_cap, 3);
+    }
+
+    #[test]
+    fn streaming_serialization() {
+        let hll = HyperLogLog::from_iter_with(8, 0..1000).unwrap();
+        let other = HyperLogLog::from_iter_with(6, 0..50).unwrap();
+        let mut buf = Vec::new();
+        hll.to_writer(&mut buf).unwrap();
+        assert_eq!(buf, hll.to_bytes());
+        buf.extend_from_slice(&other.to_bytes_compressed());
+
+        // 続けて書き込んだスケッチを順に読み込める
+        let mut r = io::Cursor::new(buf);
+        assert_eq!(HyperLogLog::from_reader(&mut r).unwrap().registers, hll.registers);
+        assert_eq!(HyperLogLog::from_reader(&mut r).unwrap().registers, other.registers);
+        assert!(HyperLogLog::from_reader(&mut r).is_err());
+
+        let mut bytes = hll.to_bytes();
+        bytes.push(0);
+        assert!(HyperLogLog::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn run_length_encoding() {
+        let mut hll = HyperLogLog::new(14).unwrap();
+        for i in 0..1000 {
+            hll.insert(&i);
+        }
+        let runs = hll.registers_rle();
+        assert_eq!(runs.iter().map(|&(_, len)| len as usize).sum::<usize>(), hll.m);
+        assert!(runs.windows(2).all(|w| w[0].0 != w[1].0));
+        // 値(1バイト)と連長(4バイト)の組でも、レジスタの配列より小さい
+        assert!(runs.len() * 5 < hll.m, "{} runs", runs.len());
+
+        let (key0, key1) = hll.keys();
+        let restored = HyperLogLog::from_rle(14, key0, key1, &runs).unwrap();
+        assert_eq!(restored.registers, hll.registers);
+        assert!(restored.compatible_with(&hll));
+
+        assert!(HyperLogLog::from_rle(14, key0, key1, &runs[1..]).is_err());
+        assert!(HyperLogLog::from_rle(4, key0, key1, &[(70, 16)]).is_err());
+    }
+
+    #[test]
+    fn base64() {
+        let hll = HyperLogLog::from_iter_with(8, 0..100).unwrap();
+        let encoded = hll.registers_base64();
+        assert!(encoded.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'+' || c == b'/' || c == b'='));
+        let restored = HyperLogLog::from_base64(&encoded).unwrap();
+        assert_eq!(restored.registers, hll.registers);
+        assert!(restored.compatible_with(&hll));
+
+        assert!(HyperLogLog::from_base64("not base64!").is_err());
+        assert!(HyperLogLog::from_base64("AAAA").is_err());
+    }
+
+    #[test]
+    fn recycle() {
+        let mut hll = HyperLogLog::with_seed(10, 7).unwrap();
+        hll.enable_recency_tracking();
+        for i in 0..1000 {
+            hll.insert(&i);
+        }
+        let old = hll.clone();
+        hll.recycle().unwrap();
+        assert_eq!(hll.b, 10);
+        assert!(hll.is_empty());
+        assert_eq!(hll.cardinality(), 0.0);
+        assert_eq!(hll.insert_sequence(), 0);
+        assert_eq!(hll.key_source(), KeySource::Os);
+        assert_ne!(hll.keys(), old.keys());
+        assert!(hll.clone().merge(&old).is_err());
+
+        hll.insert(&1);
+        assert_eq!(hll.insert_sequence(), 1);
+        assert!(hll.cardinality_recent(0).unwrap() > 0.0);
+    }
+
+    #[test]
+    fn key_source() {
+        let hll = HyperLogLog::new(8).unwrap();
+        assert_eq!(hll.key_source(), KeySource::Os);
+        assert_eq!(hll.empty_like().key_source(), KeySource::Os);
+
+        let seeded = HyperLogLog::with_seed(8, 42).unwrap();
+        assert_eq!(seeded.key_source(), KeySource::Seed(42));
+        // シードから導くキーはプラットフォームによらず固定の値になる
+        assert_eq!(seeded.keys(), (0x50a9_57f1_c4ed_ded2, 0x58cb_134d_74f1_12d7));
+        assert_eq!(HyperLogLog::with_seed(10, 42).unwrap().keys(), seeded.keys());
+        assert_ne!(HyperLogLog::with_seed(8, 43).unwrap().keys(), seeded.keys());
+
+        let (key0, key1) = hll.keys();
+        let mut explicit = HyperLogLog::from_parts(8, key0, key1, vec![0; 256]).unwrap();
+        assert_eq!(explicit.key_source(), KeySource::Explicit);
+        let mut rekeyed = seeded.clone();
+        rekeyed.set_keys(1, 2).unwrap();
+        assert_eq!(rekeyed.key_source(), KeySource::Explicit);
+
+        // シリアライズしても由来は保持される
+        explicit.insert(&1);
+        for sketch in &[&hll, &seeded, &explicit] {
+            for bytes in &[sketch.to_bytes(), sketch.to_bytes_compressed()] {
+                assert_eq!(HyperLogLog::from_bytes(bytes).unwrap().key_source(), sketch.key_source());
+            }
+        }
+
+        // 旧形式は由来を含まないので`Explicit`として読み込む
+        let bytes = seeded.to_bytes();
+        let mut legacy = bytes[..HEADER_V1_LEN].to_vec();
+        legacy[0] = FORMAT_DENSE_V1;
+        legacy.extend_from_slice(&bytes[HEADER_LEN..bytes.len() - CHECKSUM_LEN]);
+        let restored = HyperLogLog::from_bytes(&legacy).unwrap();
+        assert_eq!(restored.key_source(), KeySource::Explicit);
+        assert_eq!(restored.keys(), seeded.keys());
+
+        // 記録されたシードとキーが一致しなければ壊れているとみなす
+        let mut bad = bytes.clone();
+        bad[HEADER_V1_LEN + 1] ^= 1;
+        assert!(HyperLogLog::from_bytes(&bad).is_err());
+        let mut bad = bytes.clone();
+        bad[HEADER_V1_LEN] = 9;
+        assert!(HyperLogLog::from_bytes(&bad).is_err());
+    }
+
+    #[test]
+    fn checksum() {
+        let mut hll = HyperLogLog::new(8).unwrap();
+        for i in 0..100 {
+            hll.insert(&i);
+        }
+        for bytes in &[hll.to_bytes(), hll.to_bytes_compressed()] {
+            assert_eq!(HyperLogLog::from_bytes(bytes).unwrap().registers, hll.registers);
+            // どのバイトが壊れても検出できる
+            for i in 0..bytes.len() {
+                let mut bad = bytes.clone();
+                bad[i] ^= 0x04;
+                assert!(HyperLogLog::from_bytes(&bad).is_err(), "byte {}", i);
+            }
+        }
+
+        // レジスタの値が正しい範囲のまま変わっても、チェックサムで検出する
+        let mut bad = hll.to_bytes();
+        bad[HEADER_LEN + 3] ^= 0x01;
+        let err = HyperLogLog::from_bytes(&bad).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+
+        // チェックサムを含まない旧形式も読み込める
+        let bytes = hll.to_bytes();
+        let mut legacy = bytes[..bytes.len() - CHECKSUM_LEN].to_vec();
+        legacy[0] = FORMAT_DENSE_V2;
+        assert_eq!(HyperLogLog::from_bytes(&legacy).unwrap().registers, hll.registers);
+    }
+
+    #[test]
+    fn compressed_serialization_size() {
+        let mut hll = HyperLogLog::new(16).unwrap();
+        for j in 0..10 {
+            hll.set_register(j * 6000, 3).unwrap();
+        }
+        let dense = hll.to_bytes();
+        let sparse = hll.to_bytes_compressed();
+        assert_eq!(dense.len(), HEADER_LEN + 65536 + CHECKSUM_LEN);
+        assert_eq!(sparse.len(), HEADER_LEN + 4 + 10 * 3 + CHECKSUM_LEN);
+
+        let restored = HyperLogLog::from_bytes(&sparse).unwrap();
+        assert_eq!(restored.registers, hll.registers);
+    }
+
+    #[test]
+    fn from_bytes_rejects_corrupted_input() {
+        let hll = HyperLogLog::from_iter_with(4, 0..10).unwrap();
+        let dense = hll.to_bytes();
+        assert!(HyperLogLog::from_bytes(&dense[..HEADER_LEN - 1]).is_err());
+        assert!(HyperLogLog::from_bytes(&dense[..dense.len() - 1]).is_err());
+
+        let mut bad = dense.clone();
+        bad[0] = 9;
+        assert!(HyperLogLog::from_bytes(&bad).is_err());
+        let mut bad = dense.clone();
+        bad[1] = 40;
+        assert!(HyperLogLog::from_bytes(&bad).is_err());
+        let mut bad = dense.clone();
+        bad[HEADER_LEN] = 62;
+        assert!(HyperLogLog::from_bytes(&bad).is_err());
+
+        let mut sparse = HyperLogLog::new(8).unwrap();
+        sparse.set_register(1, 1).unwrap();
+        let mut bad = sparse.to_bytes_compressed();
+        bad[HEADER_LEN + 5] = 0x01;
+        assert!(HyperLogLog::from_bytes(&bad).is_err());
+
+        // チェックサムが正しくても、レジスタの値の上限が0のヘッダーは拒否する
+        let empty = HyperLogLog::new(8).unwrap().to_bytes();
+        let mut zero_cap = empty[..empty.len() - CHECKSUM_LEN].to_vec();
+        zero_cap[4] = 0;
+        let err = HyperLogLog::from_bytes(&append_checksum(zero_cap)).unwrap_err();
+        assert!(err.to_string().contains("register cap must be between 1"), "{}", err);
+    }
+}
@@ -0,0 +1,98 @@
+//! 借用したレジスタ配列に対する読み取り専用のスケッチ
+
+use std::error::Error;
+
+use estimate::{Float, get_alpha, estimate_cardinality_of_registers};
+use hash::{HashWidth, max_register_value};
+
+/// 借用したレジスタ配列をそのまま使ってカーディナリティを見積もる、読み取り専用のスケッチ。
+/// 大きなバッファの一部に格納されたレジスタを、`HyperLogLog`に複製せずに見積もるために使う。
+/// レジスタは64ビットのハッシュ値で作られたものとみなす。ハッシュのキーは保持しないので、
+/// `union_cardinality`に渡すビューが同じキーで作られていることは呼び出し側で保証すること
+#[derive(Debug, Clone, Copy)]
+pub struct HyperLogLogView<'a> {
+    b: u8,
+    alpha: Float,
+    registers: &'a [u8],
+}
+
+impl<'a> HyperLogLogView<'a> {
+
+    /// bビットのレジスタ配列registersのビューを作成する。bが範囲外、registersの長さが
+    /// 2^bでない、または値が上限(65 - b)を超えるレジスタがあれば`Err`を返す
+    pub fn new(b: u8, registers: &'a [u8]) -> Result<Self, Box<dyn Error>> {
+        let alpha = get_alpha(b)?;
+        if registers.len() != 1 << b {
+            return Err(From::from(format!("registers must have {} elements. len = {}",
+                                          1usize << b, registers.len())))
+        }
+        let max = max_register_value(HashWidth::Bits64, b);
+        if let Some(j) = registers.iter().position(|&x| x > max) {
+            return Err(From::from(format!("registers[{}] = {} exceeds the maximum value {}",
+                                          j, registers[j], max)))
+        }
+        Ok(HyperLogLogView { b, alpha, registers })
+    }
+
+    /// 精度(b)を返す
+    pub fn precision(&self) -> u8 {
+        self.b
+    }
+
+    /// 借用しているレジスタ配列を返す
+    pub fn registers(&self) -> &'a [u8] {
+        self.registers
+    }
+
+    /// カーディナリティの見積もり値を返す
+    pub fn cardinality(&self) -> f64 {
+        estimate_cardinality_of_registers(self.alpha, self.registers).0.min(HashWidth::Bits64.space())
+    }
+
+    /// otherとの和集合のカーディナリティの見積もり値を返す。どちらのレジスタも変更しない。
+    /// bが一致しなければ`Err`を返す
+    pub fn union_cardinality(&self, other: &HyperLogLogView) -> Result<f64, Box<dyn Error>> {
+        if self.b != other.b {
+            return Err(From::from(format!("b must be the same. self.b = {}, other.b = {}", self.b, other.b)))
+        }
+        let union = self.registers.iter().zip(other.registers)
+            .map(|(&x, &y)| x.max(y))
+            .collect::<Vec<_>>();
+        Ok(estimate_cardinality_of_registers(self.alpha, &union).0.min(HashWidth::Bits64.space()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use HyperLogLog;
+
+    #[test]
+    fn view_matches_owned_sketch() {
+        let empty = HyperLogLog::new(10).unwrap();
+        let mut hll1 = empty.clone();
+        let mut hll2 = empty.clone();
+        for i in 0..3000 {
+            hll1.insert(&i);
+            hll2.insert(&(i + 2000));
+        }
+        // 1つのバッファに並べたレジスタの一部をビューとして使う
+        let mut buffer = hll1.registers.clone();
+        buffer.extend_from_slice(&hll2.registers);
+        let view1 = HyperLogLogView::new(10, &buffer[..1024]).unwrap();
+        let view2 = HyperLogLogView::new(10, &buffer[1024..]).unwrap();
+        assert_eq!(view1.precision(), 10);
+        assert_eq!(view1.cardinality(), hll1.cardinality());
+
+        let mut union = hll1.clone();
+        union.merge(&hll2).unwrap();
+        assert_eq!(view1.union_cardinality(&view2).unwrap(), union.cardinality());
+
+        assert!(HyperLogLogView::new(10, &buffer[..1000]).is_err());
+        assert!(HyperLogLogView::new(3, &buffer[..8]).is_err());
+        assert!(HyperLogLogView::new(10, &[60; 1024]).is_err());
+        let small = HyperLogLogView::new(9, &buffer[..512]).unwrap();
+        assert!(view1.union_cardinality(&small).is_err());
+    }
+}